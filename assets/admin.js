@@ -0,0 +1,61 @@
+// Admin tools, enabled by opening the page with #admin={token}
+function adminToken() {
+	const params = new URLSearchParams(location.hash.slice(1));
+	return params.get("admin");
+}
+
+async function adminFetch(path, options = {}) {
+	const response = await fetch(path, {
+		...options,
+		headers: { Authorization: "Bearer " + adminToken() },
+	});
+	if (!response.ok) {
+		throw new Error(path + " failed with " + response.status);
+	}
+	return response;
+}
+
+async function showPixelOwner(info, canvas, x, y) {
+	const response = await adminFetch("/canvas/" + canvas + "/pixel/" + x + "/" + y);
+	const owner = await response.json();
+	if (owner === null) {
+		info.innerText = x + ", " + y + " was not written since the server started";
+		return;
+	}
+	const client = owner.name === null || owner.name === undefined
+		? "client " + owner.client
+		: owner.name + " (client " + owner.client + ")";
+	info.innerText = x + ", " + y + " was last written by " + client
+		+ " from " + owner.address
+		+ " at " + new Date(owner.time).toLocaleString();
+}
+
+window.addEventListener("load", function() {
+	if (!adminToken()) {
+		return;
+	}
+	const info = document.getElementById("pixelInfo");
+	info.hidden = false;
+	info.innerText = "Click a pixel to see who wrote it";
+
+	const image = document.querySelector("img.grid");
+	image.style.cursor = "crosshair";
+	image.addEventListener("click", async function(event) {
+		const rect = image.getBoundingClientRect();
+		// the canvas being viewed is the one the image streams
+		const id = Number(new URL(image.src).searchParams.get("canvas") || 0);
+		try {
+			// zoomed canvases are streamed bigger than they are, so map to the size clients see
+			const response = await fetch("/canvases");
+			const canvas = (await response.json()).find((canvas) => canvas.id === id);
+			if (!canvas) {
+				throw new Error("There is no canvas " + id);
+			}
+			const x = Math.floor((event.clientX - rect.left) / rect.width * canvas.width);
+			const y = Math.floor((event.clientY - rect.top) / rect.height * canvas.height);
+			await showPixelOwner(info, id, x, y);
+		} catch (error) {
+			info.innerText = error.message;
+		}
+	});
+});
@@ -0,0 +1,72 @@
+// Draws the samples of /stats/history as line charts, every value is shown per second
+const CHARTS = [
+	{ id: "pixelChart", label: "Pixels/s", value: (sample) => sample.pixels / sample.period },
+	{ id: "clientChart", label: "Clients", value: (sample) => sample.clients },
+	{ id: "bytesChart", label: "Bytes/s", value: (sample) => sample.bytes / sample.period },
+	{ id: "errorChart", label: "Parse errors/s", value: (sample) => sample.parse_errors / sample.period },
+];
+const chartFormatter = Intl.NumberFormat("en", { notation: "compact" });
+
+function drawChart(canvas, label, values) {
+	const ctx = canvas.getContext("2d");
+	const width = canvas.width;
+	const height = canvas.height;
+	const top = 16;
+	ctx.clearRect(0, 0, width, height);
+
+	const max = Math.max(1, ...values);
+	ctx.fillStyle = "#000000";
+	ctx.font = "12px sans-serif";
+	ctx.fillText(label + " (max " + chartFormatter.format(max) + ")", 4, 12);
+
+	ctx.strokeStyle = "#888888";
+	ctx.beginPath();
+	ctx.moveTo(0, height - 0.5);
+	ctx.lineTo(width, height - 0.5);
+	ctx.stroke();
+
+	if (values.length < 2) {
+		return;
+	}
+	ctx.strokeStyle = "#020024";
+	ctx.lineWidth = 1.5;
+	ctx.beginPath();
+	values.forEach(function(value, idx) {
+		const x = idx / (values.length - 1) * width;
+		const y = height - value / max * (height - top);
+		if (idx == 0) {
+			ctx.moveTo(x, y);
+		} else {
+			ctx.lineTo(x, y);
+		}
+	});
+	ctx.stroke();
+}
+
+// how often a new sample is added to every range, in milliseconds
+const SAMPLE_PERIODS = { seconds: 1000, minutes: 60 * 1000 };
+
+async function updateCharts(range) {
+	const response = await fetch("/stats/history?range=" + range.value);
+	if (!response.ok) {
+		console.error("Could not load the stats history", response.status);
+		return;
+	}
+	const samples = await response.json();
+	for (const chart of CHARTS) {
+		drawChart(document.getElementById(chart.id), chart.label, samples.map(chart.value));
+	}
+}
+
+window.addEventListener("load", function() {
+	const range = document.getElementById("historyRange");
+	const update = () => updateCharts(range).catch((error) => console.error(error));
+	let timer;
+	const poll = function() {
+		clearInterval(timer);
+		update();
+		timer = setInterval(update, SAMPLE_PERIODS[range.value]);
+	};
+	range.onchange = poll;
+	poll();
+});
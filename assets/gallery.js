@@ -0,0 +1,60 @@
+// milliseconds between refreshes of the canvas list
+const GALLERY_INTERVAL = 1000;
+
+const galleryFormatter = Intl.NumberFormat("en", { notation: "compact" });
+
+function describeCanvas(canvas) {
+	const size = canvas.width + " x " + canvas.height;
+	if (canvas.pixel_rate === null) {
+		return size + ", wall";
+	}
+	return size + ", " + galleryFormatter.format(canvas.pixel_rate) + " pixels/s";
+}
+
+function createCard(canvas) {
+	const card = document.createElement("a");
+	card.className = "card";
+	card.href = "/view.html?canvas=" + canvas.id;
+	const image = document.createElement("img");
+	image.src = "/imgstream?canvas=" + canvas.id + "&thumbnail=true";
+	image.alt = canvas.name;
+	image.draggable = false;
+	const name = document.createElement("strong");
+	const info = document.createElement("span");
+	card.append(image, name, info);
+	return card;
+}
+
+async function refreshGallery(gallery, cards) {
+	const response = await fetch("/canvases");
+	const canvases = await response.json();
+	// keep the cards of known canvases so their thumbnail streams stay open
+	const seen = new Set();
+	for (const canvas of canvases) {
+		seen.add(canvas.id);
+		if (!cards.has(canvas.id)) {
+			const card = createCard(canvas);
+			cards.set(canvas.id, card);
+			gallery.append(card);
+		}
+		const card = cards.get(canvas.id);
+		card.querySelector("strong").innerText = canvas.name;
+		card.querySelector("span").innerText = describeCanvas(canvas);
+	}
+	for (const [id, card] of cards) {
+		if (!seen.has(id)) {
+			card.remove();
+			cards.delete(id);
+		}
+	}
+}
+
+window.addEventListener("load", function() {
+	const gallery = document.getElementById("gallery");
+	const cards = new Map();
+	const refresh = function() {
+		refreshGallery(gallery, cards).catch((error) => console.error(error));
+	};
+	refresh();
+	setInterval(refresh, GALLERY_INTERVAL);
+});
@@ -0,0 +1,119 @@
+// Scrub through and play back the recorded frames of a canvas, open as /history.html?canvas={id}
+const PLAYBACK_TICK = 100;
+
+async function loadTimes(canvas) {
+	const response = await fetch("/history/" + canvas);
+	if (!response.ok) {
+		return [];
+	}
+	return await response.json();
+}
+
+// The index of the last frame taken at or before `time`
+function frameAt(times, time) {
+	let low = 0;
+	let high = times.length - 1;
+	while (low < high) {
+		const middle = Math.ceil((low + high) / 2);
+		if (times[middle] <= time) {
+			low = middle;
+		} else {
+			high = middle - 1;
+		}
+	}
+	return low;
+}
+
+window.addEventListener("load", async function() {
+	const params = new URLSearchParams(location.search);
+	const image = document.getElementById("frame");
+	const select = document.getElementById("canvas");
+	const play = document.getElementById("play");
+	const speed = document.getElementById("speed");
+	const slider = document.getElementById("slider");
+	const label = document.getElementById("time");
+
+	let times = [];
+	let canvas = Number(params.get("canvas") || 0);
+	let playing = null;
+	// the frame on screen and the one asked for, frames load one at a time while scrubbing
+	let shown = null;
+	let loading = false;
+
+	const show = function() {
+		if (times.length === 0) {
+			label.innerText = "Nothing recorded yet";
+			return;
+		}
+		const time = times[slider.value];
+		label.innerText = new Date(time).toLocaleString();
+		if (loading || shown === time) {
+			return;
+		}
+		loading = true;
+		shown = time;
+		image.src = "/history/" + canvas + "/frame?time=" + time;
+	};
+	image.addEventListener("load", function() {
+		loading = false;
+		show();
+	});
+	image.addEventListener("error", function() {
+		loading = false;
+	});
+
+	const stop = function() {
+		clearInterval(playing);
+		playing = null;
+		play.innerText = "Play";
+	};
+	const load = async function() {
+		stop();
+		times = await loadTimes(canvas);
+		slider.max = Math.max(times.length - 1, 0);
+		slider.value = 0;
+		shown = null;
+		show();
+	};
+
+	const response = await fetch("/canvases");
+	for (const entry of await response.json()) {
+		if (entry.kind !== "canvas") {
+			continue;
+		}
+		const option = document.createElement("option");
+		option.value = entry.id;
+		option.innerText = entry.name;
+		select.append(option);
+	}
+	select.value = canvas;
+	select.addEventListener("change", function() {
+		canvas = Number(select.value);
+		load();
+	});
+	slider.addEventListener("input", function() {
+		stop();
+		show();
+	});
+	play.addEventListener("click", function() {
+		if (playing) {
+			stop();
+			return;
+		}
+		if (Number(slider.value) >= times.length - 1) {
+			slider.value = 0;
+		}
+		play.innerText = "Pause";
+		let time = times[slider.value];
+		playing = setInterval(function() {
+			// event time moves `speed` times as fast as real time
+			time += PLAYBACK_TICK * Number(speed.value);
+			slider.value = frameAt(times, time);
+			show();
+			if (Number(slider.value) >= times.length - 1) {
+				stop();
+			}
+		}, PLAYBACK_TICK);
+	});
+	await load();
+});
@@ -0,0 +1,32 @@
+// Rotates through every canvas for projectors, open as /kiosk.html?interval={seconds}
+const DEFAULT_INTERVAL = 30;
+
+function rotationInterval() {
+	const params = new URLSearchParams(location.search);
+	const seconds = Number(params.get("interval"));
+	return (seconds > 0 ? seconds : DEFAULT_INTERVAL) * 1000;
+}
+
+window.addEventListener("load", function() {
+	const image = document.getElementById("canvas");
+	const overlay = document.getElementById("overlay");
+	let current = -1;
+
+	const next = async function() {
+		// fetched every time so canvases added or resized at runtime show up
+		const response = await fetch("/canvases");
+		const canvases = await response.json();
+		if (canvases.length === 0) {
+			return;
+		}
+		current = (current + 1) % canvases.length;
+		const canvas = canvases[current];
+		image.src = "/imgstream?canvas=" + canvas.id;
+		overlay.innerText = canvas.name + " - " + canvas.width + " x " + canvas.height;
+	};
+	const rotate = function() {
+		next().catch((error) => console.error(error));
+	};
+	rotate();
+	setInterval(rotate, rotationInterval());
+});
@@ -3,14 +3,34 @@ function nString(value) {
 	return formatter.format(value);
 }
 
+// milliseconds between updates, the pixel rate is averaged over one second of them
+const UPDATE_INTERVAL = 200;
+
+function renderLeaderboard(body, leaderboard) {
+	body.replaceChildren(...leaderboard.map(function(entry) {
+		const row = document.createElement("tr");
+		const client = document.createElement("td");
+		client.innerText = entry.client;
+		const pixels = document.createElement("td");
+		pixels.innerText = nString(entry.pixels);
+		row.append(client, pixels);
+		return row;
+	}));
+}
+
 window.onload = function() {
 	var client = document.getElementById("clientCounter");
 	var pixel = document.getElementById("pixelCounter");
 	var pixelAvg = document.getElementById("pixelCounterAvg");
+	var encodeTime = document.getElementById("encodeTime");
+	var runtimeLag = document.getElementById("runtimeLag");
+	var rejected = document.getElementById("rejectedCounter");
+	var recordingBytes = document.getElementById("recordingBytes");
+	var leaderboard = document.getElementById("leaderboard");
 
 	var pixelQueue = [];
 
-	for (i = 0; i < 5; i++) {
+	for (i = 0; i < 1000 / UPDATE_INTERVAL; i++) {
 		pixelQueue.push(0);
 	}
 
@@ -18,6 +38,10 @@ window.onload = function() {
 
 	stats.onopen = function() {
 		console.log("Connected to flut-stats.");
+		stats.send(JSON.stringify({
+			topics: ["global", "leaderboard"],
+			interval: UPDATE_INTERVAL,
+		}));
 	};
 	stats.onerror = function(error) {
 		console.error("An unknown error occured", error);
@@ -29,11 +53,22 @@ window.onload = function() {
 
 	stats.onmessage = function(event) {
 		const obj = JSON.parse(event.data);
-		client.innerText = nString(obj.c);
+		if (obj.global) {
+			const global = obj.global;
+			client.innerText = nString(global.clients);
+
+			pixel.innerText = nString(global.pixels);
+			pixelQueue.push(global.pixels);
+			var old = pixelQueue.shift();
+			pixelAvg.innerText = nString(global.pixels - old);
 
-		pixel.innerText = nString(obj.p);
-		pixelQueue.push(obj.p);
-		var old = pixelQueue.shift();
-		pixelAvg.innerText = nString(obj.p - old);
+			encodeTime.innerText = (global.encode_micros / 1000).toFixed(1) + " ms";
+			runtimeLag.innerText = (global.runtime_lag_micros / 1000).toFixed(1) + " ms";
+			rejected.innerText = nString(global.rejected);
+			recordingBytes.innerText = nString(global.recording_bytes) + "B";
+		}
+		if (obj.leaderboard) {
+			renderLeaderboard(leaderboard, obj.leaderboard);
+		}
 	};
 };
@@ -0,0 +1,86 @@
+// How far one step of the mouse wheel zooms
+const ZOOM_STEP = 1.2;
+const MAX_ZOOM = 64;
+
+// The part of the image the stream is drawn in, the rest is letterboxing from object-fit
+function contentRect(image) {
+	const rect = image.getBoundingClientRect();
+	const scale = Math.min(rect.width / image.naturalWidth, rect.height / image.naturalHeight);
+	const width = image.naturalWidth * scale;
+	const height = image.naturalHeight * scale;
+	return {
+		left: rect.left + (rect.width - width) / 2,
+		top: rect.top + (rect.height - height) / 2,
+		width: width,
+		height: height,
+	};
+}
+
+function canvasParameter() {
+	const params = new URLSearchParams(location.search);
+	return Number(params.get("canvas") || 0);
+}
+
+window.addEventListener("load", async function() {
+	const id = canvasParameter();
+	const image = document.getElementById("canvas");
+	const overlay = document.getElementById("overlay");
+	image.src = "/imgstream?canvas=" + id;
+
+	const response = await fetch("/canvases");
+	const canvas = (await response.json()).find((canvas) => canvas.id === id);
+	if (!canvas) {
+		overlay.innerText = "There is no canvas " + id;
+		return;
+	}
+	document.title = "Flurry - " + canvas.name;
+	overlay.innerText = canvas.name;
+
+	let zoom = 1;
+	let panX = 0;
+	let panY = 0;
+	let drag = null;
+	const apply = function() {
+		image.style.transform = "translate(" + panX + "px, " + panY + "px) scale(" + zoom + ")";
+	};
+
+	image.addEventListener("wheel", function(event) {
+		event.preventDefault();
+		const factor = event.deltaY < 0 ? ZOOM_STEP : 1 / ZOOM_STEP;
+		const next = Math.min(Math.max(zoom * factor, 1), MAX_ZOOM);
+		// keep the point under the cursor where it is
+		const rect = image.getBoundingClientRect();
+		const offsetX = event.clientX - rect.left;
+		const offsetY = event.clientY - rect.top;
+		panX -= offsetX * (next / zoom - 1);
+		panY -= offsetY * (next / zoom - 1);
+		zoom = next;
+		if (zoom === 1) {
+			panX = 0;
+			panY = 0;
+		}
+		apply();
+	}, { passive: false });
+
+	image.addEventListener("pointerdown", function(event) {
+		drag = { x: event.clientX - panX, y: event.clientY - panY };
+		image.setPointerCapture(event.pointerId);
+	});
+	image.addEventListener("pointerup", function() {
+		drag = null;
+	});
+	image.addEventListener("pointermove", function(event) {
+		if (drag) {
+			panX = event.clientX - drag.x;
+			panY = event.clientY - drag.y;
+			apply();
+		}
+		// the stream is scaled up for zoomed canvases, so map to the size clients see
+		const rect = contentRect(image);
+		const x = Math.floor((event.clientX - rect.left) / rect.width * canvas.width);
+		const y = Math.floor((event.clientY - rect.top) / rect.height * canvas.height);
+		if (x >= 0 && y >= 0 && x < canvas.width && y < canvas.height) {
+			overlay.innerText = canvas.name + " - " + x + ", " + y;
+		}
+	});
+});
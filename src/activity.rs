@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use image::{Rgb, RgbImage};
+
+use crate::clock;
+
+/// Per pixel write counters and last write times of a canvas, enabled with
+/// [`Flut::with_activity`](crate::grid::Flut::with_activity).
+///
+/// Both are updated with relaxed atomics in the set path, so they cost two stores per pixel but
+/// never lock.
+pub struct Activity {
+    size_x: usize,
+    size_y: usize,
+    writes: Box<[AtomicU32]>,
+    /// the [`clock`] of the last write, 0 if the pixel was never written
+    last_write: Box<[AtomicU32]>,
+}
+
+impl Activity {
+    pub(crate) fn new(size_x: usize, size_y: usize) -> Activity {
+        let len = size_x * size_y;
+        Activity {
+            size_x,
+            size_y,
+            writes: (0..len).map(|_| AtomicU32::new(0)).collect(),
+            last_write: (0..len).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn record(&self, idx: usize) {
+        // saturate instead of wrapping, so the busiest pixels stay the hottest
+        if self.writes[idx].load(Ordering::Relaxed) != u32::MAX {
+            self.writes[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_write[idx].store(clock::now(), Ordering::Relaxed);
+    }
+
+    /// How often the pixel at `idx` was written since the last reset
+    pub fn writes(&self, idx: usize) -> u32 {
+        self.writes[idx].load(Ordering::Relaxed)
+    }
+
+    /// Seconds since the pixel at `idx` was last written, `None` if it wasn't since the last reset
+    pub fn age(&self, idx: usize) -> Option<u32> {
+        match self.last_write[idx].load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(clock::now().saturating_sub(last)),
+        }
+    }
+
+    /// Forget every write so far
+    pub fn reset(&self) {
+        for (writes, last_write) in self.writes.iter().zip(self.last_write.iter()) {
+            writes.store(0, Ordering::Relaxed);
+            last_write.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Render the write counters on a log scale, the busiest pixels are white and pixels that
+    /// were never written are black
+    pub fn heatmap(&self) -> RgbImage {
+        let max = (0..self.writes.len())
+            .map(|idx| self.writes(idx))
+            .max()
+            .unwrap_or_default();
+        let scale = (max as f32).ln_1p();
+        self.render(|idx| match self.writes(idx) {
+            0 => 0.0,
+            writes => (writes as f32).ln_1p() / scale,
+        })
+    }
+
+    /// Render the time since the last write on a log scale, the most recently written pixels are
+    /// white and pixels that were never written are black
+    pub fn age_map(&self) -> RgbImage {
+        let scale = (clock::now() as f32).ln_1p();
+        self.render(|idx| match self.age(idx) {
+            None => 0.0,
+            Some(age) => 1.0 - (age as f32).ln_1p() / scale,
+        })
+    }
+
+    fn render(&self, heat: impl Fn(usize) -> f32) -> RgbImage {
+        RgbImage::from_fn(self.size_x as u32, self.size_y as u32, |x, y| {
+            heat_color(heat(y as usize * self.size_x + x as usize))
+        })
+    }
+}
+
+/// Map `0.0..=1.0` from black over red and yellow to white
+fn heat_color(heat: f32) -> Rgb<u8> {
+    let channel = |offset: f32| ((heat * 3.0 - offset).clamp(0.0, 1.0) * 255.0) as u8;
+    Rgb([channel(0.0), channel(1.0), channel(2.0)])
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_reset() {
+        let activity = Activity::new(2, 2);
+        activity.record(1);
+        activity.record(1);
+        activity.record(2);
+        assert_eq!(activity.writes(1), 2);
+        assert_eq!(activity.age(1), Some(0));
+        assert_eq!(activity.age(0), None);
+
+        let heatmap = activity.heatmap();
+        assert_eq!(heatmap.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(heatmap.get_pixel(1, 0), &Rgb([255, 255, 255]));
+        assert_ne!(heatmap.get_pixel(0, 1), &Rgb([0, 0, 0]));
+        assert_eq!(activity.age_map().get_pixel(1, 0), &Rgb([255, 255, 255]));
+
+        activity.reset();
+        assert_eq!(activity.writes(1), 0);
+        assert_eq!(activity.age(1), None);
+    }
+}
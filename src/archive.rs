@@ -0,0 +1,440 @@
+//! A lossless history of a canvas: a keyframe with every pixel, followed by the tiles that
+//! changed since the frame before, every record compressed on its own.
+//!
+//! An archive starts with [`MAGIC`], then records follow back to back. Every record is a header
+//! of a kind byte, the unix timestamp in milliseconds as `i64`, the width and height as `u32`
+//! and the length of the payload as `u32`, all little endian, and the deflated payload. The
+//! payload of a keyframe is every pixel as `u32` row by row, the payload of a delta is the
+//! index of every changed tile as `u32` followed by its pixels xor the previous frame, row by
+//! row and cut off at the edges of the canvas.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use crate::{config::MAX_CANVAS_PIXELS, grid::Frame, Coordinate};
+
+/// The first bytes of every archive
+pub const MAGIC: &[u8; 8] = b"FLURARC1";
+/// The side of the square tiles deltas are made of
+const TILE_SIDE: usize = 16;
+/// A keyframe is written after this many deltas, so reading a frame never replays more
+const KEYFRAME_INTERVAL: usize = 256;
+const HEADER_LENGTH: usize = 1 + 8 + 4 + 4 + 4;
+
+const KEYFRAME: u8 = 0;
+const DELTA: u8 = 1;
+
+/// Where a record is in an archive and what it holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub keyframe: bool,
+    /// unix timestamp in milliseconds
+    pub time: i64,
+    pub size_x: usize,
+    pub size_y: usize,
+    /// where the payload starts
+    offset: u64,
+    length: usize,
+}
+
+/// The tiles of a `size_x` by `size_y` canvas, as the ranges of pixels of every row of a tile
+fn tile_rows(
+    size_x: usize,
+    size_y: usize,
+    tile: usize,
+) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let tiles_x = size_x.div_ceil(TILE_SIDE);
+    let (x, y) = ((tile % tiles_x) * TILE_SIDE, (tile / tiles_x) * TILE_SIDE);
+    let width = TILE_SIDE.min(size_x - x);
+    (y..(y + TILE_SIDE).min(size_y)).map(move |row| row * size_x + x..row * size_x + x + width)
+}
+
+fn tile_count(size_x: usize, size_y: usize) -> usize {
+    size_x.div_ceil(TILE_SIDE) * size_y.div_ceil(TILE_SIDE)
+}
+
+/// Appends frames to an archive as they are taken
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    /// the size and pixels of the last frame that was written
+    previous: Option<(usize, usize, Vec<u32>)>,
+    deltas: usize,
+}
+
+impl ArchiveWriter<BufWriter<File>> {
+    /// Continue the archive at `path`, or start it if there is none. The first frame written is
+    /// a keyframe either way.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be opened or isn't an archive
+    pub fn open(path: &Path) -> io::Result<ArchiveWriter<BufWriter<File>>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            return ArchiveWriter::new(BufWriter::new(file));
+        }
+        // a record a crash cut off would hide every record appended after it
+        let end = records(&mut file)?
+            .last()
+            .map_or(MAGIC.len() as u64, |record| {
+                record.offset + record.length as u64
+            });
+        file.set_len(end)?;
+        Ok(ArchiveWriter {
+            writer: BufWriter::new(file),
+            previous: None,
+            deltas: 0,
+        })
+    }
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Start a new archive in `writer`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the header can't be written
+    pub fn new(mut writer: W) -> io::Result<ArchiveWriter<W>> {
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        Ok(ArchiveWriter {
+            writer,
+            previous: None,
+            deltas: 0,
+        })
+    }
+
+    /// Append `frame`, taken at the unix timestamp `time` in milliseconds. Nothing is written
+    /// if it is the same as the last frame, returns whether something was. Every record is
+    /// flushed, so a crash leaves at most the last one incomplete.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the record can't be written
+    pub fn append(&mut self, time: i64, frame: &Frame) -> io::Result<bool> {
+        let (size_x, size_y) = frame.get_size();
+        let pixels = frame.pixels();
+        let (kind, payload) = match &self.previous {
+            Some((previous_x, previous_y, previous))
+                if (*previous_x, *previous_y) == (size_x, size_y)
+                    && self.deltas < KEYFRAME_INTERVAL =>
+            {
+                let mut payload = Vec::new();
+                for tile in 0..tile_count(size_x, size_y) {
+                    let changed = tile_rows(size_x, size_y, tile)
+                        .any(|row| pixels[row.clone()] != previous[row]);
+                    if !changed {
+                        continue;
+                    }
+                    payload.extend_from_slice(&(tile as u32).to_le_bytes());
+                    for row in tile_rows(size_x, size_y, tile) {
+                        for (new, old) in pixels[row.clone()].iter().zip(&previous[row]) {
+                            payload.extend_from_slice(&(new ^ old).to_le_bytes());
+                        }
+                    }
+                }
+                if payload.is_empty() {
+                    return Ok(false);
+                }
+                self.deltas += 1;
+                (DELTA, payload)
+            }
+            _ => {
+                self.deltas = 0;
+                let payload = pixels
+                    .iter()
+                    .flat_map(|pixel| pixel.to_le_bytes())
+                    .collect();
+                (KEYFRAME, payload)
+            }
+        };
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload)?;
+        let payload = encoder.finish()?;
+
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.push(kind);
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&(size_x as u32).to_le_bytes());
+        header.extend_from_slice(&(size_y as u32).to_le_bytes());
+        header.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&payload)?;
+        self.writer.flush()?;
+        self.previous = Some((size_x, size_y, pixels.to_vec()));
+        Ok(true)
+    }
+}
+
+/// Every complete record of an archive, in the order they were written. An incomplete record
+/// at the end, like one a crash cut off, is left out.
+///
+/// # Errors
+///
+/// This function will return an error if `reader` fails or isn't an archive
+pub fn records<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Record>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a flurry archive",
+        ));
+    }
+    let mut records = Vec::new();
+    let mut offset = MAGIC.len() as u64;
+    while offset + HEADER_LENGTH as u64 <= end {
+        let mut header = [0; HEADER_LENGTH];
+        reader.read_exact(&mut header)?;
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let record = Record {
+            keyframe: match header[0] {
+                KEYFRAME => true,
+                DELTA => false,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unknown record kind",
+                    ))
+                }
+            },
+            time: i64::from_le_bytes(header[1..9].try_into().unwrap()),
+            size_x: field(9) as usize,
+            size_y: field(13) as usize,
+            offset: offset + HEADER_LENGTH as u64,
+            length: field(17) as usize,
+        };
+        // canvases are never this big, so neither are their frames
+        if record.size_x > Coordinate::MAX as usize
+            || record.size_y > Coordinate::MAX as usize
+            || record.size_x * record.size_y > MAX_CANVAS_PIXELS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record is too big",
+            ));
+        }
+        offset = record.offset + record.length as u64;
+        if offset > end {
+            break;
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Inflate the payload of `record`, refusing to inflate more than a record of its size can hold
+fn read_payload<R: Read + Seek>(reader: &mut R, record: &Record) -> io::Result<Vec<u8>> {
+    // every pixel, and for deltas the index of every tile
+    let limit = (record.size_x * record.size_y + tile_count(record.size_x, record.size_y)) * 4;
+    reader.seek(SeekFrom::Start(record.offset))?;
+    let mut payload = Vec::new();
+    DeflateDecoder::new(reader.take(record.length as u64))
+        .take(limit as u64 + 1)
+        .read_to_end(&mut payload)?;
+    if payload.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record inflates past its size",
+        ));
+    }
+    Ok(payload)
+}
+
+/// Replay the archive up to the last frame taken at or before the unix timestamp `time` in
+/// milliseconds, or the first frame if there is none that early. Returns when that frame was
+/// taken and its pixels, `None` for an empty archive.
+///
+/// # Errors
+///
+/// This function will return an error if `reader` fails or the archive is corrupt
+pub fn read_frame<R: Read + Seek>(reader: &mut R, time: i64) -> io::Result<Option<(i64, Frame)>> {
+    let records = records(reader)?;
+    let Some(target) = records
+        .iter()
+        .rposition(|record| record.time <= time)
+        .or((!records.is_empty()).then_some(0))
+    else {
+        return Ok(None);
+    };
+    let start = records[..=target]
+        .iter()
+        .rposition(|record| record.keyframe)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "delta without keyframe"))?;
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt record");
+    let mut pixels = Vec::new();
+    let mut size = (0, 0);
+    for record in &records[start..=target] {
+        let payload = read_payload(reader, record)?;
+        let words = payload
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+        if record.keyframe {
+            pixels = words.collect();
+            if pixels.len() != record.size_x * record.size_y {
+                return Err(corrupt());
+            }
+            size = (record.size_x, record.size_y);
+            continue;
+        }
+        // a delta only applies to a frame of its own size
+        if (record.size_x, record.size_y) != size {
+            return Err(corrupt());
+        }
+        let mut words = words.peekable();
+        while let Some(tile) = words.next() {
+            if tile as usize >= tile_count(record.size_x, record.size_y) {
+                return Err(corrupt());
+            }
+            for row in tile_rows(record.size_x, record.size_y, tile as usize) {
+                for pixel in &mut pixels[row] {
+                    *pixel ^= words.next().ok_or_else(corrupt)?;
+                }
+            }
+        }
+    }
+    let record = &records[target];
+    Ok(Some((
+        record.time,
+        Frame::from_pixels(record.size_x, record.size_y, pixels),
+    )))
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn frame(size_x: usize, size_y: usize, pixels: &[(usize, u32)]) -> Frame {
+        let mut frame = vec![0x00_00_00_ff; size_x * size_y];
+        for (idx, value) in pixels {
+            frame[*idx] = *value;
+        }
+        Frame::from_pixels(size_x, size_y, frame)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut archive = Vec::new();
+        let mut writer = ArchiveWriter::new(&mut archive).unwrap();
+        let frames = [
+            (1000, frame(40, 20, &[])),
+            (2000, frame(40, 20, &[(39, 1), (799, 2)])),
+            (3000, frame(40, 20, &[(39, 1), (799, 2)])),
+            (4000, frame(40, 20, &[(0, 3)])),
+            (5000, frame(10, 10, &[(99, 4)])),
+        ];
+        let written: Vec<_> = frames
+            .iter()
+            .map(|(time, frame)| writer.append(*time, frame).unwrap())
+            .collect();
+        assert_eq!(written, [true, true, false, true, true]);
+
+        let mut reader = Cursor::new(&archive);
+        let records = records(&mut reader).unwrap();
+        let keyframes: Vec<_> = records.iter().map(|record| record.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false, true]);
+        // the delta of two changed tiles compresses well below their raw pixels
+        assert!(records[1].length < 2 * TILE_SIDE * TILE_SIDE * 4 / 8);
+
+        for (time, expected) in [(0, 1000), (2500, 2000), (3000, 2000), (4999, 4000)] {
+            let (at, read) = read_frame(&mut reader, time).unwrap().unwrap();
+            assert_eq!(at, expected);
+            let (_, original) = frames.iter().find(|(time, _)| *time == at).unwrap();
+            assert_eq!(read.get_size(), original.get_size());
+            assert_eq!(read.pixels(), original.pixels());
+        }
+        let (_, last) = read_frame(&mut reader, i64::MAX).unwrap().unwrap();
+        assert_eq!(last.get_size(), (10, 10));
+        assert_eq!(last.pixels()[99], 4);
+
+        // a record cut off by a crash is ignored
+        archive.truncate(archive.len() - 3);
+        let (at, _) = read_frame(&mut Cursor::new(&archive), i64::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(at, 4000);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.flurry");
+        let mut writer = ArchiveWriter::open(&path).unwrap();
+        writer.append(1, &frame(4, 4, &[(5, 7)])).unwrap();
+        drop(writer);
+        let mut writer = ArchiveWriter::open(&path).unwrap();
+        writer.append(2, &frame(4, 4, &[(6, 7)])).unwrap();
+        drop(writer);
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(records(&mut file).unwrap().len(), 2);
+        let (_, read) = read_frame(&mut file, 2).unwrap().unwrap();
+        assert_eq!(read.pixels()[5..7], [0x00_00_00_ff, 7]);
+
+        // a crash cut the last record off, the next run appends after the complete ones
+        let length = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(length - 3).unwrap();
+        let mut writer = ArchiveWriter::open(&path).unwrap();
+        writer.append(3, &frame(4, 4, &[(7, 7)])).unwrap();
+        drop(writer);
+        let mut file = File::open(&path).unwrap();
+        let times: Vec<_> = records(&mut file)
+            .unwrap()
+            .iter()
+            .map(|record| record.time)
+            .collect();
+        assert_eq!(times, [1, 3]);
+        let (_, read) = read_frame(&mut file, 3).unwrap().unwrap();
+        assert_eq!(read.pixels()[5..8], [0x00_00_00_ff, 0x00_00_00_ff, 7]);
+
+        std::fs::write(&path, b"not an archive").unwrap();
+        assert!(ArchiveWriter::open(&path).is_err());
+    }
+
+    /// A record as a crafted archive could contain it
+    fn record(kind: u8, size_x: u32, size_y: u32, payload: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload).unwrap();
+        let payload = encoder.finish().unwrap();
+        let mut record = vec![kind];
+        record.extend_from_slice(&0i64.to_le_bytes());
+        record.extend_from_slice(&size_x.to_le_bytes());
+        record.extend_from_slice(&size_y.to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    #[test]
+    fn test_crafted_archives_are_refused() {
+        let keyframe = record(KEYFRAME, 1, 1, &[0; 4]);
+        // a delta of a tile far outside the keyframe
+        let mut delta = 17u32.to_le_bytes().to_vec();
+        delta.extend_from_slice(&[0xff; 16 * 16 * 4]);
+        let archive = [&MAGIC[..], &keyframe, &record(DELTA, 1024, 1024, &delta)].concat();
+        let err = read_frame(&mut Cursor::new(archive), 0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a tiny record that inflates to far more than a 1x1 keyframe
+        let archive = [&MAGIC[..], &record(KEYFRAME, 1, 1, &vec![0; 1 << 20])].concat();
+        assert!(archive.len() < 4096);
+        let err = read_frame(&mut Cursor::new(archive), 0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
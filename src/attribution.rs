@@ -0,0 +1,85 @@
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{clients::ClientId, clock};
+
+/// Which client last wrote every pixel of a canvas and when, enabled with
+/// [`Flut::with_attribution`](crate::grid::Flut::with_attribution).
+///
+/// The client and the time are packed into one atomic per pixel, so they always belong to the
+/// same write.
+pub struct Attribution {
+    size_x: usize,
+    size_y: usize,
+    /// the client in the upper half, the [`clock`] of the write in the lower half, 0 if the
+    /// pixel was never written
+    owners: Box<[AtomicU64]>,
+}
+
+impl Attribution {
+    pub(crate) fn new(size_x: usize, size_y: usize) -> Attribution {
+        Attribution {
+            size_x,
+            size_y,
+            owners: (0..size_x * size_y).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn record(&self, idx: usize, client: ClientId) {
+        self.owners[idx].store(
+            (client as u64) << 32 | clock::now() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The position of the pixel at `x`, `y`, `None` if it is outside the canvas this tracks
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.size_x && y < self.size_y).then_some(y * self.size_x + x)
+    }
+
+    /// Add every client that owns a pixel to `owners`
+    pub(crate) fn collect_owners(&self, owners: &mut HashSet<ClientId>) {
+        owners.extend(
+            self.owners
+                .iter()
+                .map(|owner| (owner.load(Ordering::Relaxed) >> 32) as ClientId),
+        );
+    }
+
+    /// The client that last wrote the pixel at `idx` and when, `None` if it was never written
+    pub fn owner(&self, idx: usize) -> Option<(ClientId, DateTime<Utc>)> {
+        match self.owners[idx].load(Ordering::Relaxed) {
+            0 => None,
+            owner => Some(((owner >> 32) as ClientId, clock::time_of(owner as u32))),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_owner() {
+        let attribution = Attribution::new(2, 1);
+        assert_eq!(attribution.owner(0), None);
+        attribution.record(0, 7);
+        let (client, time) = attribution.owner(0).unwrap();
+        assert_eq!(client, 7);
+        assert!((Utc::now() - time).num_seconds() <= 1);
+        attribution.record(0, 0);
+        assert_eq!(attribution.owner(0).unwrap().0, 0);
+        assert_eq!(attribution.index(1, 0), Some(1));
+        assert_eq!(attribution.index(0, 1), None);
+        let mut owners = HashSet::new();
+        attribution.record(1, 9);
+        attribution.collect_owners(&mut owners);
+        assert_eq!(owners, HashSet::from([0, 9]));
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        LazyLock, RwLock,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Identifies a connection for pixel attribution, 0 is used for writes that don't come from a
+/// client
+pub type ClientId = u32;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientInfo {
+    pub address: SocketAddr,
+    /// What the client called itself with `NAME`
+    pub name: Option<String>,
+    pub connected: DateTime<Utc>,
+}
+
+struct Client {
+    info: ClientInfo,
+    disconnected: bool,
+}
+
+static NEXT_CLIENT: AtomicU32 = AtomicU32::new(1);
+
+/// The clients that are connected or still own an attributed pixel, the others are removed by
+/// [`forget_clients`]
+static CLIENT_INFOS: LazyLock<RwLock<HashMap<ClientId, Client>>> = LazyLock::new(RwLock::default);
+
+/// Give a new connection from `address` an id
+pub fn register_client(address: SocketAddr) -> ClientId {
+    let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+    CLIENT_INFOS
+        .write()
+        .expect("RWlock didn't exit nicely")
+        .insert(
+            id,
+            Client {
+                info: ClientInfo {
+                    address,
+                    name: None,
+                    connected: Utc::now(),
+                },
+                disconnected: false,
+            },
+        );
+    id
+}
+
+/// Mark a client as gone, it is kept while it owns a pixel
+pub fn disconnect_client(id: ClientId) {
+    if let Some(client) = CLIENT_INFOS
+        .write()
+        .expect("RWlock didn't exit nicely")
+        .get_mut(&id)
+    {
+        client.disconnected = true;
+    }
+}
+
+/// Give a client the name it chose, replacing the one it had
+pub fn name_client(id: ClientId, name: String) {
+    if let Some(client) = CLIENT_INFOS
+        .write()
+        .expect("RWlock didn't exit nicely")
+        .get_mut(&id)
+    {
+        client.info.name = Some(name);
+    }
+}
+
+/// Remove every disconnected client that isn't in `owners`
+pub(crate) fn forget_clients(owners: &HashSet<ClientId>) {
+    CLIENT_INFOS
+        .write()
+        .expect("RWlock didn't exit nicely")
+        .retain(|id, client| !client.disconnected || owners.contains(id));
+}
+
+pub fn client_info(id: ClientId) -> Option<ClientInfo> {
+    CLIENT_INFOS
+        .read()
+        .expect("RWlock didn't exit nicely")
+        .get(&id)
+        .map(|client| client.info.clone())
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_forget() {
+        let address = "192.0.2.1:1234".parse().unwrap();
+        let owner = register_client(address);
+        let connected = register_client(address);
+        let gone = register_client(address);
+        assert_ne!(owner, 0);
+        assert_eq!(client_info(owner).unwrap().address, address);
+        assert_eq!(client_info(0), None);
+        assert_eq!(client_info(owner).unwrap().name, None);
+        name_client(owner, "team red".to_string());
+        assert_eq!(
+            client_info(owner).unwrap().name.as_deref(),
+            Some("team red")
+        );
+
+        disconnect_client(owner);
+        disconnect_client(gone);
+        forget_clients(&HashSet::from([owner]));
+        assert!(client_info(owner).is_some());
+        assert!(client_info(connected).is_some());
+        assert_eq!(client_info(gone), None);
+    }
+}
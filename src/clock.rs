@@ -0,0 +1,49 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        LazyLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// How often the clock is advanced, so it is never more than this behind
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A seconds counter for the set path, reading it is a relaxed load instead of asking the OS
+/// for the time of every pixel
+struct Clock {
+    started: DateTime<Utc>,
+    /// seconds since `started` plus one, so 0 can stand for never
+    seconds: AtomicU32,
+}
+
+/// Started by the first write that needs it, a thread advances it once per second after that
+static CLOCK: LazyLock<Clock> = LazyLock::new(|| {
+    let start = Instant::now();
+    thread::Builder::new()
+        .name("flurry-clock".to_string())
+        .spawn(move || loop {
+            thread::sleep(TICK_INTERVAL);
+            let seconds = start.elapsed().as_secs() as u32 + 1;
+            CLOCK.seconds.store(seconds, Ordering::Relaxed);
+        })
+        .expect("Could not spawn the clock thread");
+    Clock {
+        started: Utc::now(),
+        seconds: AtomicU32::new(1),
+    }
+});
+
+/// Seconds since the clock started plus one, never 0
+#[inline]
+pub(crate) fn now() -> u32 {
+    CLOCK.seconds.load(Ordering::Relaxed)
+}
+
+/// The time `seconds` returned by [`now`] stand for
+pub(crate) fn time_of(seconds: u32) -> DateTime<Utc> {
+    CLOCK.started + TimeDelta::seconds(seconds as i64 - 1)
+}
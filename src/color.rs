@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use rand::{distr::StandardUniform, prelude::Distribution};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Color {
     RGB24(u8, u8, u8),
     RGBA32(u8, u8, u8, u8),
@@ -1,11 +1,71 @@
 use std::time::Duration;
 
+use crate::{grid::ImageFit, recordings::Retention, video::VideoSink, wall::Tile, Protocol};
+
 pub const GRID_LENGTH: usize = 1;
+/// Show every pixel as a block this big, the canvases get fewer pixels so the image stays the size
+pub const CANVAS_SCALE: usize = 1;
+/// Virtual canvases made of tiles of the real ones, addressed as the canvases after them
+pub const WALLS: &[&[Tile]] = &[];
+/// An image every canvas starts out as instead of a solid color, also what admins reset it to
+pub const INITIAL_IMAGE: Option<&str> = None;
+pub const INITIAL_IMAGE_FIT: ImageFit = ImageFit::Scale;
+/// An image shown behind every canvas where the user layer is transparent
+pub const BACKGROUND_IMAGE: Option<&str> = None;
+/// An image drawn on top of every canvas in the outputs, like a watermark or the server address
+pub const OVERLAY_IMAGE: Option<&str> = None;
+/// Let pixelflut clients read the background and overlay instead of just the user layer
+pub const READ_COMPOSITE: bool = false;
+/// Keep per pixel write counters and last write times for the heatmap and age images
+pub const TRACK_ACTIVITY: bool = false;
+/// Remember which client last wrote every pixel, admins can look it up in the web interface
+pub const TRACK_ATTRIBUTION: bool = false;
+/// The bearer token admin requests to the web interface need, admin requests are refused without
+pub const ADMIN_TOKEN: Option<&str> = None;
 pub const HOST: &str = "127.0.0.1:7791";
+/// Pick the protocol of a new connection on `HOST` from the first byte it sends
+pub const DETECT_PROTOCOL: bool = true;
+/// Extra listeners that skip detection and always start in the given protocol
+pub const FORCED_PROTOCOL_HOSTS: &[(&str, Protocol)] = &[];
 pub const WEB_HOST: &str = "127.0.0.1:3000";
 pub const IMAGE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Which recorded frames are kept, every one of them unless limits are set
+pub const RECORDING_RETENTION: Retention = Retention {
+    max_bytes: None,
+    max_age: None,
+    thinning: &[],
+};
+/// How often the lossless archives in `./archives` get the changes of every canvas, `None` to
+/// keep no archives
+pub const ARCHIVE_INTERVAL: Option<Duration> = None;
+/// Raw frames of canvases for external encoders, like `VideoSink { canvas: 0, fps: 30,
+/// format: PixelFormat::Rgb24, target: SinkTarget::Path("/tmp/flurry.fifo".into()) }`
+pub fn video_sinks() -> Vec<VideoSink> {
+    Vec::new()
+}
 pub const JPEG_UPDATE_INTERVAL: Duration = Duration::from_millis(17);
 pub const WEB_UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+/// How often the thumbnails in the gallery are updated
+pub const THUMBNAIL_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// How many distinct views of canvases are encoded at once, streams of further views are refused
+pub const MAX_VIEWS: usize = 64;
+/// The most pixels a canvas can be resized to, every pixel costs at least 4 bytes per copy
+pub const MAX_CANVAS_PIXELS: usize = 8192 * 8192;
+/// The longest name a pixelflut client can give itself with `NAME`, in bytes
+pub const MAX_CLIENT_NAME: usize = 64;
+/// How often the stats are collected, also the fastest rate a stats subscriber can ask for
+pub const STATS_INTERVAL: Duration = Duration::from_millis(100);
+/// The slowest rate a stats subscriber can ask for
+pub const STATS_MAX_INTERVAL: Duration = Duration::from_secs(60);
+/// How many clients the stats leaderboard lists
+pub const STATS_LEADERBOARD_SIZE: usize = 10;
+/// How many addresses the leaderboard counts pixels for, the disconnected ones with the fewest
+/// pixels are forgotten first
+pub const STATS_TRACKED_CLIENTS: usize = 4096;
+/// How many per second samples `/stats/history` keeps
+pub const HISTORY_SECONDS: usize = 600;
+/// How many per minute samples `/stats/history` keeps
+pub const HISTORY_MINUTES: usize = 24 * 60;
 
 pub const HELP_TEXT: &[u8] = b"Flurry is a pixelflut implementation, this means you can use commands to get and set pixels in the canvas
 SIZE returns the size of the canvas
@@ -14,4 +74,5 @@ If you include a color in hex format you set a pixel instead
 PX {x} {y} {RGB} sets the color of the pixel at {x}, {y} to the rgb value
 PX {x} {y} {RGBA} blends the pixel at {x}, {y} with the rgb value weighted by the a
 PX {x} {y} {W} sets the color of the pixel at {x}, {y} to the grayscale value
+NAME {name} names this connection for the admins of the server
 ";
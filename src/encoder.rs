@@ -0,0 +1,171 @@
+use std::{
+    fs::{create_dir_all, File},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::Ordering,
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{grid::Flut, wall::Wall, ENCODE_MICROS};
+
+/// How many recorded frames can wait for the disk before new ones are dropped
+const WRITE_QUEUE_LENGTH: usize = 16;
+
+/// Start one thread per canvas and wall that keeps its jpeg up to date, so encoding runs in
+/// parallel and never blocks the async runtime. The threads stop once `shutdown` is cancelled.
+///
+/// # Errors
+///
+/// This function will return an error if a thread can't be spawned
+pub(crate) fn spawn_jpeg_encoders(
+    grids: Arc<[Flut<u32>]>,
+    walls: Arc<[Wall]>,
+    interval: Duration,
+    shutdown: CancellationToken,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    let canvases = (0..grids.len()).map(|canvas| {
+        let grids = grids.clone();
+        spawn_encoder(
+            format!("flurry-jpeg-{canvas}"),
+            interval,
+            shutdown.clone(),
+            move || grids[canvas].update_jpg_buffer(),
+        )
+    });
+    let walls = (0..walls.len()).map(|wall| {
+        let grids = grids.clone();
+        let walls = walls.clone();
+        spawn_encoder(
+            format!("flurry-wall-{wall}"),
+            interval,
+            shutdown.clone(),
+            move || walls[wall].update_jpg_buffer(&grids),
+        )
+    });
+    canvases.chain(walls).collect()
+}
+
+fn spawn_encoder(
+    name: String,
+    interval: Duration,
+    shutdown: CancellationToken,
+    update: impl Fn() -> bool + Send + 'static,
+) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name(name).spawn(move || {
+        while !shutdown.is_cancelled() {
+            let start = Instant::now();
+            if update() {
+                ENCODE_MICROS.store(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+            }
+            thread::sleep(interval.saturating_sub(start.elapsed()));
+        }
+    })
+}
+
+/// Writes recorded frames to disk on a dedicated thread, so slow disks never block the runtime
+pub(crate) struct RecordingWriter {
+    sender: SyncSender<(PathBuf, Vec<u8>)>,
+}
+
+impl RecordingWriter {
+    /// Start the writer thread, it stops when the writer is dropped. Frames that can't be
+    /// written are logged and skipped, so a full or flaky disk only costs those frames.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the thread can't be spawned
+    pub(crate) fn spawn(base_dir: PathBuf) -> io::Result<RecordingWriter> {
+        let (sender, receiver) = sync_channel::<(PathBuf, Vec<u8>)>(WRITE_QUEUE_LENGTH);
+        thread::Builder::new()
+            .name("flurry-recordings".to_string())
+            .spawn(move || {
+                while let Ok((name, data)) = receiver.recv() {
+                    let path = base_dir.join(name);
+                    if let Err(err) = RecordingWriter::write_frame(&path, &data) {
+                        tracing::error!("Could not write recording {path:?}: {err:?}");
+                    }
+                }
+            })?;
+        Ok(RecordingWriter { sender })
+    }
+
+    fn write_frame(path: &Path, data: &[u8]) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        File::create(path)?.write_all(data)
+    }
+
+    /// Queue a file to be written, dropping it if the disk can't keep up. Returns whether it
+    /// was queued. `name` may lead through directories, they are created as needed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `BrokenPipe` if the writer thread is gone, which only happens
+    /// if it panicked
+    pub(crate) fn write(&self, name: PathBuf, data: Vec<u8>) -> io::Result<bool> {
+        match self.sender.try_send((name, data)) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full((name, _))) => {
+                tracing::warn!("Dropped recording {name:?}, the disk can't keep up");
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    #[test]
+    fn test_encoders_update_and_stop() {
+        let grids: Arc<[Flut<u32>]> = [Flut::init(8, 8, 0), Flut::init(4, 4, 0)].into();
+        let shutdown = CancellationToken::new();
+        let handles = spawn_jpeg_encoders(
+            grids.clone(),
+            Arc::new([]),
+            Duration::from_millis(1),
+            shutdown.clone(),
+        )
+        .unwrap();
+        grids[1].set(1, 1, 0xff_00_00_ff);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while grids.iter().any(|grid| grid.read_jpg_buffer().is_empty()) {
+            assert!(Instant::now() < deadline, "encoders never produced a jpeg");
+            thread::sleep(Duration::from_millis(1));
+        }
+        shutdown.cancel();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_recording_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = RecordingWriter::spawn(dir.path().join("recordings")).unwrap();
+        // a file where the directory of canvas 1 should be fails that write but not the next
+        std::fs::create_dir_all(dir.path().join("recordings")).unwrap();
+        std::fs::write(dir.path().join("recordings/1"), [0]).unwrap();
+        assert!(writer.write("1/frame.jpg".into(), vec![4]).unwrap());
+        assert!(writer.write("0/frame.jpg".into(), vec![1, 2, 3]).unwrap());
+        drop(writer);
+        let path = dir.path().join("recordings/0/frame.jpg");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read(&path).ok() != Some(vec![1, 2, 3]) {
+            assert!(Instant::now() < deadline, "frame was never written");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
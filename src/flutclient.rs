@@ -1,16 +1,30 @@
 use std::{
     io::{self, Error, ErrorKind},
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
 };
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadBuf,
+};
 
 use crate::{
+    clients::{name_client, ClientId},
+    config::DETECT_PROTOCOL,
     get_pixel,
-    grid::{self, Flut},
+    grid::{self, Batch, Flut},
     increment_counter,
-    protocols::{BinaryParser, IOProtocol, Parser, Responder, TextParser},
-    set_pixel_rgba, Canvas, Color, Command, Coordinate, Protocol, ProtocolStatus, Response,
+    protocols::{
+        custom_protocol_names, detect_protocol, BinaryParser, CustomParser, IOProtocol, Parser,
+        Responder, TextParser,
+    },
+    wall::Wall,
+    Canvas, Color, Command, Coordinate, Protocol, ProtocolStatus, Response, BYTES, PARSE_ERRORS,
+    REJECTED,
 };
 
 macro_rules! build_parser_type_enum {
@@ -22,6 +36,7 @@ macro_rules! build_parser_type_enum {
                 #[cfg(feature = $feat)]
                 $name($t),
             )*
+            Custom(CustomParser),
         }
 
         impl std::default::Default for ParserTypes {
@@ -36,24 +51,37 @@ macro_rules! build_parser_type_enum {
         }
 
         impl ParserTypes {
-            pub fn get_status() -> Vec<ProtocolStatus> {
+            /// The protocols built into flurry, whether their feature is enabled or not
+            pub(crate) fn builtin_status() -> Vec<ProtocolStatus> {
                 vec![
                 $(
                     #[cfg(feature = $feat)]
-                    ProtocolStatus::Enabled($feat),
+                    ProtocolStatus::Enabled($feat.into()),
                     #[cfg(not(feature = $feat))]
-                    ProtocolStatus::Disabled($feat),
+                    ProtocolStatus::Disabled($feat.into()),
                 )*
                 ]
             }
 
+            /// Every protocol this build knows about, the built in ones followed by the
+            /// registered ones. This is what `PROTOCOLS` answers with.
+            pub fn get_status() -> Vec<ProtocolStatus> {
+                let mut status = ParserTypes::builtin_status();
+                status.extend(
+                    custom_protocol_names()
+                        .into_iter()
+                        .map(|name| ProtocolStatus::Enabled(name.into())),
+                );
+                status
+            }
+
             pub fn announce() {
-                $(
-                    #[cfg(feature = $feat)]
-                    tracing::info!("Enabled {}", $feat);
-                    #[cfg(not(feature = $feat))]
-                    tracing::info!("Disabled {}", $feat);
-                )*
+                for protocol in ParserTypes::get_status() {
+                    match protocol {
+                        ProtocolStatus::Enabled(name) => tracing::info!("Enabled {}", name),
+                        ProtocolStatus::Disabled(name) => tracing::info!("Disabled {}", name),
+                    }
+                }
             }
         }
 
@@ -64,6 +92,7 @@ macro_rules! build_parser_type_enum {
                         #[cfg(feature = $feat)]
                         ParserTypes::$name($pident) => $f,
                     )*
+                    ParserTypes::Custom($pident) => $f,
                 }
             )
         }
@@ -75,22 +104,63 @@ build_parser_type_enum! {
     BinaryParser: BinaryParser: "binary",
 }
 
+/// Counts the bytes read through it, so a client only touches the global counter once a batch
+struct CountingReader<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R> CountingReader<R> {
+    /// The bytes read since the last call
+    fn take_read(&mut self) -> u64 {
+        std::mem::take(&mut self.read)
+    }
+}
+
+impl<R: AsyncRead + std::marker::Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read += (buf.filled().len() - before) as u64;
+        poll
+    }
+}
+
 pub struct FlutClient<R, W>
 where
-    R: AsyncReadExt + std::marker::Unpin,
-    W: AsyncWriteExt + std::marker::Unpin,
+    R: AsyncReadExt + std::marker::Unpin + Send,
+    W: AsyncWriteExt + std::marker::Unpin + Send,
 {
-    reader: BufReader<R>,
+    reader: BufReader<CountingReader<R>>,
     writer: BufWriter<W>,
     grids: Arc<[Flut<u32>]>,
+    /// addressed as the canvases after `grids`
+    walls: Arc<[Wall]>,
     parser: ParserTypes,
+    detect_protocol: bool,
     counter: u64,
+    /// pixels not set in the current batch because they are protected
+    rejected: u64,
+    /// pixels set per canvas in the current batch
+    canvas_counters: Box<[u64]>,
+    /// bitset of the canvases written to in the current batch
+    written: [u64; 4],
+    /// the batches of the canvases in `written`
+    batches: Box<[Option<Batch<u32>>]>,
+    /// the leaderboard entry of the address this client connected from
+    client_counter: Option<Arc<AtomicU64>>,
+    /// who the pixels this client sets are attributed to
+    client: ClientId,
 }
 
 impl<R, W> FlutClient<R, W>
 where
-    R: AsyncReadExt + std::marker::Unpin,
-    W: AsyncWriteExt + std::marker::Unpin,
+    R: AsyncReadExt + std::marker::Unpin + Send,
+    W: AsyncWriteExt + std::marker::Unpin + Send,
 {
     async fn help_command(&mut self) -> io::Result<()> {
         match_parser!(parser: self.parser => parser.unparse(Response::Help, &mut self.writer).await?);
@@ -107,8 +177,21 @@ where
         Ok(())
     }
 
+    /// The wall `canvas` addresses, `None` if it is a real canvas
+    fn wall(&self, canvas: Canvas) -> Option<&Wall> {
+        let wall = (canvas as usize).checked_sub(self.grids.len())?;
+        self.walls.get(wall)
+    }
+
     async fn size_command(&mut self, canvas: Canvas) -> io::Result<()> {
-        let (x, y) = self.grids[canvas as usize].get_size();
+        let (x, y) = match self.wall(canvas) {
+            Some(wall) => wall.get_size(),
+            None => self
+                .grids
+                .get(canvas as usize)
+                .ok_or(Error::from(ErrorKind::InvalidInput))?
+                .get_size(),
+        };
         match_parser!(parser: self.parser => parser.unparse(
             Response::Size(Coordinate::try_from(x).unwrap(), Coordinate::try_from(y).unwrap()), &mut self.writer).await?);
 
@@ -122,7 +205,11 @@ where
         x: Coordinate,
         y: Coordinate,
     ) -> io::Result<()> {
-        let color = match get_pixel(&self.grids, canvas, x, y) {
+        let color = match self.wall(canvas) {
+            Some(wall) => wall.read(&self.grids, x, y),
+            None => get_pixel(&self.grids, canvas, x, y),
+        };
+        let color = match color {
             None => return Err(Error::from(ErrorKind::InvalidInput)),
             Some(color) => color.to_be_bytes(),
         };
@@ -140,14 +227,76 @@ where
             }
             Color::W8(white) => u32::from_be_bytes([*white, *white, *white, 0xff]),
         };
-        set_pixel_rgba(self.grids.as_ref(), canvas, x, y, c);
+        let located = match self.wall(canvas) {
+            Some(wall) => wall.locate(x, y),
+            None => Some((canvas, x, y)),
+        };
+        // the gaps of a wall and missing canvases swallow pixels like the outside of a canvas
+        let Some((canvas, x, y, grid)) = located
+            .and_then(|(canvas, x, y)| Some((canvas, x, y, self.grids.get(canvas as usize)?)))
+        else {
+            self.counter += 1;
+            return;
+        };
+        let batch = self.batches[canvas as usize].get_or_insert_with(|| {
+            self.written[canvas as usize / 64] |= 1 << (canvas % 64);
+            grid.begin_batch()
+        });
+        if !grid.set_in(batch, x, y, c, self.client) {
+            self.rejected += 1;
+            return;
+        }
         self.counter += 1;
+        self.canvas_counters[canvas as usize] += 1;
+    }
+
+    /// Count the pixels of this batch and end the batch of every canvas that was written to
+    fn finish_batch(&mut self) {
+        increment_counter(self.counter);
+        BYTES.fetch_add(self.reader.get_mut().take_read(), Ordering::Relaxed);
+        if self.rejected != 0 {
+            REJECTED.fetch_add(self.rejected, Ordering::Relaxed);
+            self.rejected = 0;
+        }
+        if let Some(client_counter) = &self.client_counter {
+            client_counter.fetch_add(self.counter, Ordering::Relaxed);
+        }
+        self.counter = 0;
+        for (canvas, count) in self.canvas_counters.iter_mut().enumerate() {
+            if *count != 0 {
+                self.grids[canvas].add_pixels(std::mem::take(count));
+            }
+        }
+        self.end_batches();
+    }
+
+    /// End the batch of every canvas that was written to since the last call
+    fn end_batches(&mut self) {
+        for (word_idx, word) in self.written.iter_mut().enumerate() {
+            while *word != 0 {
+                let canvas = word_idx * 64 + word.trailing_zeros() as usize;
+                if let Some(batch) = self.batches[canvas].take() {
+                    self.grids[canvas].end_batch(batch);
+                }
+                *word &= *word - 1;
+            }
+        }
     }
 
     fn change_canvas_command(&mut self, canvas: Canvas) -> io::Result<()> {
+        if canvas as usize >= self.grids.len() + self.walls.len() {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
         match_parser!(parser: self.parser => parser.change_canvas(canvas))
     }
 
+    /// Only connections that are attributed have an entry the name can go to
+    fn name_command(&mut self, name: String) {
+        if self.client != 0 {
+            name_client(self.client, name);
+        }
+    }
+
     fn change_protocol(&mut self, protocol: &Protocol) {
         match protocol {
             #[cfg(feature = "text")]
@@ -164,23 +313,79 @@ where
                 self.writer.write(b"feature \"binary\" is not enabled.");
                 self.writer.flush();
             }
+            Protocol::Custom(name) => match CustomParser::new(name) {
+                Some(parser) => self.parser = ParserTypes::Custom(parser),
+                None => tracing::warn!("protocol {name} is not registered"),
+            },
         }
     }
 
+    /// Peek at the first byte of the connection and switch to the protocol it belongs to,
+    /// without consuming anything so the parser still sees the whole first command.
+    async fn detect_protocol(&mut self) -> io::Result<()> {
+        let Some(&first) = self.reader.fill_buf().await?.first() else {
+            // the connection closed before sending anything, the parser will report the EOF
+            return Ok(());
+        };
+        let protocol = detect_protocol(first);
+        tracing::debug!("Detected {protocol:?} protocol");
+        self.change_protocol(&protocol);
+        Ok(())
+    }
+
     pub fn new(reader: R, writer: W, grids: Arc<[grid::Flut<u32>]>) -> Self {
         FlutClient {
-            reader: BufReader::new(reader),
+            reader: BufReader::new(CountingReader {
+                inner: reader,
+                read: 0,
+            }),
             writer: BufWriter::new(writer),
-            grids,
             parser: ParserTypes::default(),
+            detect_protocol: DETECT_PROTOCOL,
             counter: 0,
+            rejected: 0,
+            canvas_counters: vec![0; grids.len()].into_boxed_slice(),
+            written: [0; 4],
+            batches: (0..grids.len()).map(|_| None).collect(),
+            client_counter: None,
+            client: 0,
+            grids,
+            walls: Arc::new([]),
         }
     }
 
+    /// Let this client address `walls` as the canvases after the real ones
+    pub fn set_walls(&mut self, walls: Arc<[Wall]>) {
+        self.walls = walls;
+    }
+
+    /// Attribute every pixel this client sets to `client`
+    pub fn identify(&mut self, client: ClientId) {
+        self.client = client;
+    }
+
+    /// Also add every pixel this client sets to `counter`, used for the leaderboard
+    pub fn count_pixels_in(&mut self, counter: Arc<AtomicU64>) {
+        self.client_counter = Some(counter);
+    }
+
+    /// Start the connection in `protocol` instead of detecting it from the first bytes
+    pub fn force_protocol(&mut self, protocol: &Protocol) {
+        self.change_protocol(protocol);
+        self.detect_protocol = false;
+    }
+
     pub async fn process_socket(&mut self) -> io::Result<()> {
+        if self.detect_protocol {
+            self.detect_protocol().await?;
+        }
         loop {
             match_parser!(parser: &self.parser.clone() => 'outer: loop {
                 for _ in 0..1000 {
+                    // a client that waits for more input shouldn't hold its batch open meanwhile
+                    if self.reader.buffer().is_empty() && self.written != [0; 4] {
+                        self.finish_batch();
+                    }
                     let parsed = parser.parse(&mut self.reader).await;
                     match parsed {
                         Ok(Command::Help) => self.help_command().await?,
@@ -196,20 +401,141 @@ where
                             self.change_protocol(&protocol);
                             break 'outer;
                         }
+                        Ok(Command::Name(name)) => self.name_command(name),
                         Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
                             tracing::error!("Process socket got error: {err:?}");
-                            increment_counter(self.counter);
+                            self.finish_batch();
                             return Ok(())
                         }
                         Err(e) => {
                             tracing::error!("Process socket got error: {e:?}");
+                            if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::InvalidData) {
+                                PARSE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                            }
+                            self.finish_batch();
                             return Err(e)
                         }
                     }
                 }
-                increment_counter(self.counter);
-                self.counter = 0;
+                self.finish_batch();
             });
         }
     }
 }
+
+impl<R, W> Drop for FlutClient<R, W>
+where
+    R: AsyncReadExt + std::marker::Unpin + Send,
+    W: AsyncWriteExt + std::marker::Unpin + Send,
+{
+    fn drop(&mut self) {
+        // a connection that is aborted mid batch would otherwise keep snapshots from ever
+        // finding a boundary
+        self.end_batches();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+    use crate::protection::ProtectedRegion;
+
+    fn grids() -> Arc<[Flut<u32>]> {
+        [Flut::init(800, 600, 0xff_00_ff_ff)].into()
+    }
+
+    #[tokio::test]
+    async fn test_detect_text() {
+        let reader = tokio_test::io::Builder::new().read(b"SIZE\n").build();
+        let writer = tokio_test::io::Builder::new()
+            .write(b"SIZE 800 600\n")
+            .build();
+        let mut client = FlutClient::new(reader, writer, grids());
+        // the text parser reports the closed connection as invalid input
+        let _ = client.process_socket().await;
+    }
+
+    #[tokio::test]
+    async fn test_detect_binary() {
+        let reader = tokio_test::io::Builder::new().read(&[0x73, 0x00]).build();
+        let writer = tokio_test::io::Builder::new()
+            .write(&[0x03, 0x20, 0x02, 0x58])
+            .build();
+        let mut client = FlutClient::new(reader, writer, grids());
+        client.process_socket().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_detect_keeps_first_command() {
+        let grids = grids();
+        let reader = tokio_test::io::Builder::new()
+            .read(&[0x80, 0x00, 0x00, 0x01, 0x00, 0x02, 0x12, 0x34, 0x56])
+            .build();
+        let writer = tokio_test::io::Builder::new().build();
+        let mut client = FlutClient::new(reader, writer, grids.clone());
+        client.process_socket().await.unwrap();
+        assert_eq!(get_pixel(&grids, 0, 1, 2), Some(0x12_34_56_ff));
+        assert_eq!(grids[0].generation(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_size_of_missing_canvas() {
+        let reader = tokio_test::io::Builder::new().read(&[0x73, 0x05]).build();
+        let writer = tokio_test::io::Builder::new().build();
+        let mut client = FlutClient::new(reader, writer, grids());
+        let err = client.process_socket().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_forced_protocol() {
+        let reader = tokio_test::io::Builder::new().read(&[0x73, 0x00]).build();
+        let writer = tokio_test::io::Builder::new().build();
+        let mut client = FlutClient::new(reader, writer, grids());
+        client.force_protocol(&Protocol::Text);
+        assert!(client.process_socket().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pixels_are_counted() {
+        let grids = grids();
+        let reader = tokio_test::io::Builder::new()
+            .read(&[0x80, 0x00, 0x00, 0x01, 0x00, 0x02, 0x12, 0x34, 0x56])
+            .read(&[0x80, 0x00, 0x00, 0x02, 0x00, 0x02, 0x12, 0x34, 0x56])
+            .build();
+        let writer = tokio_test::io::Builder::new().build();
+        let counter = Arc::new(AtomicU64::new(0));
+        let mut client = FlutClient::new(reader, writer, grids.clone());
+        client.count_pixels_in(counter.clone());
+        client.process_socket().await.unwrap();
+        assert_eq!(grids[0].pixels(), 2);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_protected_pixels_are_rejected() {
+        let grids = grids();
+        grids[0]
+            .protection()
+            .add(ProtectedRegion::Rect {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+            })
+            .unwrap();
+        let reader = tokio_test::io::Builder::new()
+            .read(&[0x80, 0x00, 0x00, 0x01, 0x00, 0x01, 0x12, 0x34, 0x56])
+            .read(&[0x80, 0x00, 0x00, 0x02, 0x00, 0x02, 0x12, 0x34, 0x56])
+            .build();
+        let writer = tokio_test::io::Builder::new().build();
+        let rejected = REJECTED.load(Ordering::Relaxed);
+        let mut client = FlutClient::new(reader, writer, grids.clone());
+        client.process_socket().await.unwrap();
+        assert_eq!(get_pixel(&grids, 0, 1, 1), Some(0xff_00_ff_ff));
+        assert_eq!(get_pixel(&grids, 0, 2, 2), Some(0x12_34_56_ff));
+        assert_eq!(grids[0].pixels(), 1);
+        assert!(REJECTED.load(Ordering::Relaxed) > rejected);
+    }
+}
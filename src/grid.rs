@@ -1,49 +1,131 @@
 use std::{
-    cell::SyncUnsafeCell,
     hash::{DefaultHasher, Hash, Hasher},
-    sync::{RwLock, RwLockReadGuard},
+    io::{self, Error, ErrorKind},
+    iter,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
-use image::{GenericImageView, Rgb};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgb, RgbImage, RgbaImage,
+};
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::{
+    activity::Activity,
+    attribution::Attribution,
+    clients::ClientId,
+    config::MAX_CANVAS_PIXELS,
+    layers::{render_image, Layers},
+    protection::Protection,
+    Coordinate,
+};
 
-use crate::Coordinate;
+pub use crate::layers::ImageFit;
 
 pub trait Grid<I, V> {
-    fn get(&self, x: I, y: I) -> Option<&V>;
+    fn get(&self, x: I, y: I) -> Option<V>;
     #[allow(dead_code)]
-    fn get_unchecked(&self, x: I, y: I) -> &V;
+    fn get_unchecked(&self, x: I, y: I) -> V;
     fn set(&self, x: I, y: I, value: V);
 }
 
-pub struct Flut<T> {
+/// A value that can be stored in a [`Flut`].
+///
+/// Cells are read and written with relaxed atomics, so clients can race on the same pixel
+/// without undefined behaviour, the last write simply wins.
+pub trait Cell: Copy {
+    type Atomic: Send + Sync;
+
+    fn new(value: Self) -> Self::Atomic;
+    fn load(cell: &Self::Atomic) -> Self;
+    fn store(cell: &Self::Atomic, value: Self);
+}
+
+impl Cell for u32 {
+    type Atomic = AtomicU32;
+
+    #[inline]
+    fn new(value: Self) -> Self::Atomic {
+        AtomicU32::new(value)
+    }
+
+    #[inline]
+    fn load(cell: &Self::Atomic) -> Self {
+        cell.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn store(cell: &Self::Atomic, value: Self) {
+        cell.store(value, Ordering::Relaxed)
+    }
+}
+
+pub struct Flut<T: Cell> {
+    storage: ArcSwap<Storage<T>>,
+    generation: AtomicU64,
+    /// how many batches of writes are in progress, snapshots at a boundary wait for 0
+    writers: AtomicU64,
+    pixels: AtomicU64,
+    last_hash: AtomicU64,
+    /// the frame the latest jpeg was encoded from
+    frame: Mutex<Frame>,
+    /// the frame before it, snapshots are copied into it to reuse its allocation
+    spare: Mutex<Frame>,
+    jpg: watch::Sender<Bytes>,
+    protection: Protection,
+    read_composite: AtomicBool,
+    /// locked for the whole of a resize, so changes to the layers can't get lost in between
+    sources: Mutex<Sources>,
+    /// what the canvas started out as where there is no initial image
+    fill: T,
+    /// how many image pixels wide and high every pixel is in the stream and snapshots
+    scale: usize,
+    /// shown in the gallery instead of the number of the canvas
+    name: Option<String>,
+}
+
+/// Everything about a canvas that depends on its size, replaced as a whole by [`Flut::resize`].
+/// Readers and writers load it once per operation, so they never mix up two sizes.
+struct Storage<T: Cell> {
     size_x: usize,
     size_y: usize,
-    cells: SyncUnsafeCell<Box<[T]>>,
-    last_hash: SyncUnsafeCell<u64>,
-    jpgbuf: RwLock<Vec<u8>>,
+    cells: Box<[T::Atomic]>,
+    activity: Option<Arc<Activity>>,
+    attribution: Option<Arc<Attribution>>,
+    layers: Layers,
+    /// what the canvas started out as, written back by [`Flut::reset_region`]
+    initial: Option<Box<[T]>>,
 }
 
-impl<T: Clone> Flut<T> {
-    pub fn init(size_x: usize, size_y: usize, value: T) -> Flut<T> {
-        let mut vec = Vec::with_capacity(size_x * size_y);
-        for _ in 0..(size_x * size_y) {
-            vec.push(value.clone());
-        }
-        Flut {
+/// A batch of writes to a canvas, from [`Flut::begin_batch`] to [`Flut::end_batch`]. It holds on
+/// to the storage, so the writes of the batch don't load it again for every pixel. Writes of a
+/// batch that races with a resize are lost, like single writes that race with it.
+pub struct Batch<T: Cell> {
+    storage: Arc<Storage<T>>,
+}
+
+impl<T: Cell> Storage<T> {
+    fn new(size_x: usize, size_y: usize, values: impl IntoIterator<Item = T>) -> Storage<T> {
+        Storage {
             size_x,
             size_y,
-            cells: vec.into_boxed_slice().into(),
-            last_hash: 0.into(),
-            jpgbuf: RwLock::new(Vec::new()),
+            cells: values.into_iter().map(T::new).collect(),
+            activity: None,
+            attribution: None,
+            layers: Layers::default(),
+            initial: None,
         }
     }
 
-    pub fn get_size(&self) -> (usize, usize) {
-        (self.size_x, self.size_y)
-    }
-}
-
-impl<T> Flut<T> {
+    /// The position of the pixel at `x`, `y` in the cells, `None` if it is outside the canvas
+    #[inline]
     fn index(&self, x: Coordinate, y: Coordinate) -> Option<usize> {
         let x = x as usize;
         let y = y as usize;
@@ -52,94 +134,713 @@ impl<T> Flut<T> {
         }
         Some((y * self.size_x) + x)
     }
-    pub fn read_jpg_buffer(&self) -> RwLockReadGuard<'_, Vec<u8>> {
-        self.jpgbuf.read().expect("RWlock didn't exit nicely")
+
+    #[inline]
+    fn write(&self, idx: usize, value: T, client: ClientId) {
+        T::store(&self.cells[idx], value);
+        if let Some(activity) = &self.activity {
+            activity.record(idx);
+        }
+        if let Some(attribution) = &self.attribution {
+            attribution.record(idx, client);
+        }
     }
 }
 
-impl<T> Grid<Coordinate, T> for Flut<T> {
-    fn get(&self, x: Coordinate, y: Coordinate) -> Option<&T> {
-        self.index(x, y)
-            .map(|idx| unsafe { &(&(*self.cells.get()))[idx] })
+/// The images the layers and the initial pixels of a canvas are rendered from, kept to render
+/// them again for a new size
+#[derive(Default)]
+struct Sources {
+    background: Option<DynamicImage>,
+    overlay: Option<DynamicImage>,
+    initial: Option<(DynamicImage, ImageFit)>,
+}
+
+/// What happens to the pixels of a canvas when it is resized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Keep every pixel as it is and pin the old content to a side of the new canvas
+    Anchor(Anchor),
+    /// Stretch the old content to the new size
+    Scale,
+}
+
+/// Where the old content ends up on a resized canvas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Anchor {
+    /// How far the old content moves on each axis to stay at the anchor
+    fn offset(self, old: (usize, usize), new: (usize, usize)) -> (isize, isize) {
+        let end = |old: usize, new: usize| new as isize - old as isize;
+        let (end_x, end_y) = (end(old.0, new.0), end(old.1, new.1));
+        match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::TopRight => (end_x, 0),
+            Anchor::BottomLeft => (0, end_y),
+            Anchor::BottomRight => (end_x, end_y),
+            Anchor::Center => (end_x / 2, end_y / 2),
+        }
     }
+}
 
-    fn set(&self, x: Coordinate, y: Coordinate, value: T) {
-        match self.index(x, y) {
-            None => (),
-            Some(idx) => unsafe { (&mut (*self.cells.get()))[idx] = value },
+/// A copy of a canvas taken by [`Flut::snapshot`], encoders read from this instead of the live
+/// canvas so a frame doesn't change halfway through being encoded.
+#[derive(Clone)]
+pub struct Frame {
+    size_x: usize,
+    size_y: usize,
+    /// how many image pixels wide and high every canvas pixel is when encoded
+    scale: usize,
+    generation: u64,
+    pixels: Vec<u32>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame {
+            size_x: 0,
+            size_y: 0,
+            scale: 1,
+            generation: 0,
+            pixels: Vec::new(),
+        }
+    }
+}
+
+impl Frame {
+    pub fn new() -> Frame {
+        Frame::default()
+    }
+
+    /// A frame that wasn't copied from a canvas, like the composition of a wall
+    pub(crate) fn from_pixels(size_x: usize, size_y: usize, pixels: Vec<u32>) -> Frame {
+        Frame {
+            size_x,
+            size_y,
+            scale: 1,
+            generation: 0,
+            pixels,
         }
     }
 
-    fn get_unchecked(&self, x: Coordinate, y: Coordinate) -> &T {
-        let idx = y as usize * self.size_x + x as usize;
-        unsafe { &(&(*self.cells.get()))[idx] }
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.size_x, self.size_y)
+    }
+
+    /// The generation of the canvas when the copy finished
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The pixels as `0xRRGGBBAA`, row by row
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// A hash of the pixels, to tell whether two frames show the same
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.pixels.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// How many image pixels wide and high every canvas pixel is when encoded
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// The frame as it is encoded, every canvas pixel is a block of `scale` by `scale` pixels
+    pub fn to_rgb_image(&self) -> RgbImage {
+        let image = RgbImage::from_vec(self.size_x as u32, self.size_y as u32, self.to_rgb_bytes())
+            .expect("frame has a pixel for every coordinate");
+        self.upscale(image)
+    }
+
+    /// Like [`Frame::to_rgb_image`], keeping the alpha of the pixels
+    pub fn to_rgba_image(&self) -> RgbaImage {
+        let bytes = self
+            .pixels
+            .iter()
+            .flat_map(|pixel| pixel.to_be_bytes())
+            .collect();
+        let image = RgbaImage::from_vec(self.size_x as u32, self.size_y as u32, bytes)
+            .expect("frame has a pixel for every coordinate");
+        self.upscale(image)
+    }
+
+    fn upscale<P: Pixel + 'static>(
+        &self,
+        image: ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        if self.scale == 1 {
+            return image;
+        }
+        let scale = self.scale as u32;
+        imageops::resize(
+            &image,
+            image.width() * scale,
+            image.height() * scale,
+            FilterType::Nearest,
+        )
+    }
+
+    /// The `width` by `height` rectangle at `x`, `y`, clipped to the frame, with every pixel
+    /// encoded `zoom` times as big as in this frame
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize, zoom: usize) -> Frame {
+        let x = x.min(self.size_x);
+        let y = y.min(self.size_y);
+        let width = width.min(self.size_x - x);
+        let height = height.min(self.size_y - y);
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in y..y + height {
+            let start = row * self.size_x + x;
+            pixels.extend_from_slice(&self.pixels[start..start + width]);
+        }
+        Frame {
+            size_x: width,
+            size_y: height,
+            scale: self.scale * zoom.max(1),
+            generation: self.generation,
+            pixels,
+        }
+    }
+
+    /// The pixels as packed rgb bytes, row by row
+    pub fn to_rgb_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 3);
+        for pixel in &self.pixels {
+            let [r, g, b, _a] = pixel.to_be_bytes();
+            bytes.extend_from_slice(&[r, g, b]);
+        }
+        bytes
+    }
+
+    pub fn encode_jpg(&self, quality: u8, buf: &mut Vec<u8>) -> image::ImageResult<()> {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(buf, quality);
+        self.to_rgb_image().write_with_encoder(encoder)
+    }
+
+    pub fn encode_png(&self, buf: &mut Vec<u8>) -> image::ImageResult<()> {
+        let encoder = image::codecs::png::PngEncoder::new(buf);
+        self.to_rgb_image().write_with_encoder(encoder)
     }
 }
 
-impl GenericImageView for Flut<u32> {
+impl GenericImageView for Frame {
     type Pixel = Rgb<u8>;
 
     fn dimensions(&self) -> (u32, u32) {
-        let (x, y) = self.get_size();
-        (x as u32, y as u32)
+        (self.size_x as u32, self.size_y as u32)
     }
 
     fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
-        let pixel = self.get_unchecked(x as Coordinate, y as Coordinate);
+        let pixel = self.pixels[y as usize * self.size_x + x as usize];
         let [r, g, b, _a] = pixel.to_be_bytes();
         Rgb::from([r, g, b])
     }
 }
 
-impl Flut<u32> {
-    pub fn check_changed(&self) -> bool {
-        let previous = unsafe { *self.last_hash.get() };
-        let mut hasher = DefaultHasher::new();
-        unsafe { (*self.cells.get()).hash(&mut hasher) };
-        if hasher.finish() == previous {
+impl<T: Cell> Flut<T> {
+    pub fn init(size_x: usize, size_y: usize, value: T) -> Flut<T> {
+        Flut {
+            storage: ArcSwap::from_pointee(Storage::new(
+                size_x,
+                size_y,
+                iter::repeat_n(value, size_x * size_y),
+            )),
+            generation: AtomicU64::new(0),
+            writers: AtomicU64::new(0),
+            pixels: AtomicU64::new(0),
+            last_hash: AtomicU64::new(0),
+            frame: Mutex::new(Frame::new()),
+            spare: Mutex::new(Frame::new()),
+            jpg: watch::Sender::new(Bytes::new()),
+            protection: Protection::new(size_x, size_y),
+            read_composite: AtomicBool::new(false),
+            sources: Mutex::new(Sources::default()),
+            fill: value,
+            scale: 1,
+            name: None,
+        }
+    }
+
+    /// Show every pixel as a block of `scale` by `scale` pixels in the stream and snapshots, so
+    /// a small canvas stays readable on a big screen. Clients still see the size it was created
+    /// with, `scale` is at least 1.
+    pub fn with_scale(mut self, scale: usize) -> Flut<T> {
+        self.scale = scale.max(1);
+        self
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Give the canvas a name people see instead of its number
+    pub fn with_name(mut self, name: impl Into<String>) -> Flut<T> {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Change the storage of a canvas that is still being built and isn't shared yet
+    fn map_storage(mut self, f: impl FnOnce(&mut Storage<T>)) -> Flut<T> {
+        let mut storage = Arc::try_unwrap(self.storage.into_inner())
+            .ok()
+            .expect("canvas is not shared while it is built");
+        f(&mut storage);
+        self.storage = ArcSwap::from_pointee(storage);
+        self
+    }
+
+    fn lock_sources(&self) -> MutexGuard<'_, Sources> {
+        self.sources.lock().expect("Could not lock canvas sources")
+    }
+
+    /// Write what the canvas started out as back to a region, the region is clipped to the canvas
+    pub fn reset_region(&self, x: usize, y: usize, width: usize, height: usize) {
+        let storage = self.storage.load();
+        for y in y..(y.saturating_add(height)).min(storage.size_y) {
+            for x in x..(x.saturating_add(width)).min(storage.size_x) {
+                let idx = y * storage.size_x + x;
+                let value = match &storage.initial {
+                    Some(initial) => initial[idx],
+                    None => self.fill,
+                };
+                storage.write(idx, value, 0);
+            }
+        }
+        self.next_generation();
+    }
+
+    /// Keep per pixel write counters and last write times, this costs 8 bytes per pixel
+    pub fn with_activity(self) -> Flut<T> {
+        self.map_storage(|storage| {
+            storage.activity = Some(Arc::new(Activity::new(storage.size_x, storage.size_y)));
+        })
+    }
+
+    /// The write counters and last write times, if they are kept. A resize starts them over.
+    pub fn activity(&self) -> Option<Arc<Activity>> {
+        self.storage.load().activity.clone()
+    }
+
+    /// Remember which client last wrote every pixel and when, this costs 8 bytes per pixel
+    pub fn with_attribution(self) -> Flut<T> {
+        self.map_storage(|storage| {
+            storage.attribution = Some(Arc::new(Attribution::new(storage.size_x, storage.size_y)));
+        })
+    }
+
+    /// Who last wrote every pixel, if it is kept. A resize starts it over.
+    pub fn attribution(&self) -> Option<Arc<Attribution>> {
+        self.storage.load().attribution.clone()
+    }
+
+    /// The regions pixelflut clients can't write to
+    pub fn protection(&self) -> &Protection {
+        &self.protection
+    }
+
+    pub fn get_size(&self) -> (usize, usize) {
+        let storage = self.storage.load();
+        (storage.size_x, storage.size_y)
+    }
+
+    /// The latest jpeg of the canvas, empty until the first encode
+    pub fn read_jpg_buffer(&self) -> Bytes {
+        self.jpg.borrow().clone()
+    }
+
+    /// Get notified of every new jpeg, a receiver that falls behind only sees the latest one
+    pub fn subscribe_jpg(&self) -> watch::Receiver<Bytes> {
+        self.jpg.subscribe()
+    }
+
+    /// Move to the next generation, for changes that are made in one go like a resize. Batches
+    /// of writes use [`Flut::begin_batch`] and [`Flut::end_batch`] instead.
+    pub fn next_generation(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Mark the start of a batch of writes, every batch has to be passed to [`Flut::end_batch`]
+    /// once it is written. Snapshots at a boundary wait until no batch is in progress, like the
+    /// odd counter of a seqlock.
+    pub fn begin_batch(&self) -> Batch<T> {
+        self.writers.fetch_add(1, Ordering::Relaxed);
+        // the writes of the batch can't be seen before it is marked as in progress
+        fence(Ordering::Release);
+        Batch {
+            storage: self.storage.load_full(),
+        }
+    }
+
+    /// Mark the end of a batch started with [`Flut::begin_batch`] and move to the next generation
+    pub fn end_batch(&self, batch: Batch<T>) {
+        drop(batch);
+        // the generation moves first, so a snapshot that sees the batch ended sees it too
+        self.next_generation();
+        self.writers.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Whether no batch of writes is in progress
+    fn settled(&self) -> bool {
+        self.writers.load(Ordering::Acquire) == 0
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Set a pixel on behalf of `client`, returns `false` without writing if the pixel is
+    /// protected. [`Grid::set`] ignores protection and attributes the write to client 0.
+    pub fn set_by(&self, x: Coordinate, y: Coordinate, value: T, client: ClientId) -> bool {
+        self.write_by(&self.storage.load(), x, y, value, client)
+    }
+
+    /// [`Flut::set_by`] as part of a batch of writes
+    pub fn set_in(
+        &self,
+        batch: &Batch<T>,
+        x: Coordinate,
+        y: Coordinate,
+        value: T,
+        client: ClientId,
+    ) -> bool {
+        self.write_by(&batch.storage, x, y, value, client)
+    }
+
+    #[inline]
+    fn write_by(
+        &self,
+        storage: &Storage<T>,
+        x: Coordinate,
+        y: Coordinate,
+        value: T,
+        client: ClientId,
+    ) -> bool {
+        let Some(idx) = storage.index(x, y) else {
+            return true;
+        };
+        if self.protection.is_protected(x as usize, y as usize) {
             return false;
         }
-        unsafe { *self.last_hash.get() = hasher.finish() }
+        storage.write(idx, value, client);
         true
     }
 
-    pub fn update_jpg_buffer(&self) {
-        if !self.check_changed() {
-            return;
+    /// Count `amount` pixels set on this canvas
+    pub fn add_pixels(&self, amount: u64) {
+        self.pixels.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// How many pixels were set on this canvas in total
+    pub fn pixels(&self) -> u64 {
+        self.pixels.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Cell> Grid<Coordinate, T> for Flut<T> {
+    fn get(&self, x: Coordinate, y: Coordinate) -> Option<T> {
+        let storage = self.storage.load();
+        storage.index(x, y).map(|idx| T::load(&storage.cells[idx]))
+    }
+
+    fn set(&self, x: Coordinate, y: Coordinate, value: T) {
+        let storage = self.storage.load();
+        if let Some(idx) = storage.index(x, y) {
+            storage.write(idx, value, 0);
+        }
+    }
+
+    fn get_unchecked(&self, x: Coordinate, y: Coordinate) -> T {
+        let storage = self.storage.load();
+        let idx = y as usize * storage.size_x + x as usize;
+        T::load(&storage.cells[idx])
+    }
+}
+
+impl Flut<u32> {
+    /// Start the canvas out as `image` instead of the fill value, pixels the image doesn't cover
+    /// keep the fill value
+    pub fn with_initial_image(mut self, image: &DynamicImage, fit: ImageFit) -> Flut<u32> {
+        let fill = self.fill;
+        self.sources
+            .get_mut()
+            .expect("Could not lock canvas sources")
+            .initial = Some((image.clone(), fit));
+        self.map_storage(|storage| {
+            let initial = render_image(image, storage.size_x, storage.size_y, fit, fill);
+            for (cell, value) in storage.cells.iter().zip(&initial) {
+                cell.store(*value, Ordering::Relaxed);
+            }
+            storage.initial = Some(initial.into_boxed_slice());
+        })
+    }
+
+    /// Show `image`, scaled to the canvas, where the user layer is transparent
+    pub fn set_background(&self, image: Option<&DynamicImage>) {
+        let mut sources = self.lock_sources();
+        let storage = self.storage.load();
+        storage
+            .layers
+            .set_background(image, storage.size_x, storage.size_y);
+        sources.background = image.cloned();
+    }
+
+    /// Draw `image`, scaled to the canvas, on top of everything that is shown
+    pub fn set_overlay(&self, image: Option<&DynamicImage>) {
+        let mut sources = self.lock_sources();
+        let storage = self.storage.load();
+        storage
+            .layers
+            .set_overlay(image, storage.size_x, storage.size_y);
+        sources.overlay = image.cloned();
+    }
+
+    /// Whether pixelflut clients read what is shown instead of the user layer
+    pub fn set_read_composite(&self, read_composite: bool) {
+        self.read_composite.store(read_composite, Ordering::Relaxed);
+    }
+
+    /// The pixel pixelflut clients read, either the user layer or what is shown
+    pub fn read(&self, x: Coordinate, y: Coordinate) -> Option<u32> {
+        let storage = self.storage.load();
+        let idx = storage.index(x, y)?;
+        let user = storage.cells[idx].load(Ordering::Relaxed);
+        if self.read_composite.load(Ordering::Relaxed) {
+            Some(storage.layers.composite(idx, user))
+        } else {
+            Some(user)
+        }
+    }
+
+    /// Change the size of the canvas while clients keep writing. The old pixels are either kept
+    /// as they are at `anchor` or scaled, the rest of the canvas starts out as the initial image
+    /// or the fill value.
+    ///
+    /// Layers and the initial image are rendered again for the new size, protected regions keep
+    /// their position and activity and attribution start over. Writes are never blocked, so a
+    /// write that lands on the old storage while it is being copied may be lost.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `InvalidInput` if a side is 0 or too long for a coordinate, or
+    /// if the canvas would have more than [`MAX_CANVAS_PIXELS`] pixels
+    pub fn resize(&self, size_x: usize, size_y: usize, mode: ResizeMode) -> io::Result<()> {
+        let max = Coordinate::MAX as usize;
+        if size_x == 0 || size_y == 0 || size_x > max || size_y > max {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        if size_x * size_y > MAX_CANVAS_PIXELS {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let sources = self.lock_sources();
+        let old = self.storage.load_full();
+        let old_pixels: Vec<u32> = old.cells.iter().map(u32::load).collect();
+        let initial = sources
+            .initial
+            .as_ref()
+            .map(|(image, fit)| render_image(image, size_x, size_y, *fit, self.fill));
+        let pixels = match mode {
+            ResizeMode::Scale => {
+                let bytes = old_pixels.iter().flat_map(|pixel| pixel.to_be_bytes());
+                let image =
+                    RgbaImage::from_vec(old.size_x as u32, old.size_y as u32, bytes.collect())
+                        .expect("canvas has a pixel for every coordinate");
+                let image = DynamicImage::ImageRgba8(image);
+                render_image(&image, size_x, size_y, ImageFit::Scale, self.fill)
+            }
+            ResizeMode::Anchor(anchor) => {
+                let mut pixels = initial
+                    .clone()
+                    .unwrap_or_else(|| vec![self.fill; size_x * size_y]);
+                let (offset_x, offset_y) =
+                    anchor.offset((old.size_x, old.size_y), (size_x, size_y));
+                for y in 0..old.size_y {
+                    let new_y = y as isize + offset_y;
+                    if new_y < 0 || new_y >= size_y as isize {
+                        continue;
+                    }
+                    for x in 0..old.size_x {
+                        let new_x = x as isize + offset_x;
+                        if new_x < 0 || new_x >= size_x as isize {
+                            continue;
+                        }
+                        pixels[new_y as usize * size_x + new_x as usize] =
+                            old_pixels[y * old.size_x + x];
+                    }
+                }
+                pixels
+            }
+        };
+
+        let mut storage = Storage::new(size_x, size_y, pixels);
+        if old.activity.is_some() {
+            storage.activity = Some(Arc::new(Activity::new(size_x, size_y)));
+        }
+        if old.attribution.is_some() {
+            storage.attribution = Some(Arc::new(Attribution::new(size_x, size_y)));
+        }
+        storage
+            .layers
+            .set_background(sources.background.as_ref(), size_x, size_y);
+        storage
+            .layers
+            .set_overlay(sources.overlay.as_ref(), size_x, size_y);
+        storage.initial = initial.map(Vec::into_boxed_slice);
+        self.protection.resize(size_x, size_y);
+        self.storage.store(Arc::new(storage));
+        drop(sources);
+        self.next_generation();
+        Ok(())
+    }
+
+    /// Set every pixel of the user layer to `frame`, like a frame read from an archive. The
+    /// canvas is resized to the frame first if their sizes differ.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the canvas can't be resized to the frame
+    pub fn restore(&self, frame: &Frame) -> io::Result<()> {
+        let (size_x, size_y) = frame.get_size();
+        if self.get_size() != (size_x, size_y) {
+            self.resize(size_x, size_y, ResizeMode::Anchor(Anchor::TopLeft))?;
+        }
+        let storage = self.storage.load();
+        // a resize racing this one keeps its own size, the frame is cut to fit
+        for y in 0..size_y.min(storage.size_y) {
+            for x in 0..size_x.min(storage.size_x) {
+                storage.write(y * storage.size_x + x, frame.pixels[y * size_x + x], 0);
+            }
+        }
+        self.next_generation();
+        Ok(())
+    }
+
+    /// Copy the whole canvas as it is shown into `frame` in one pass, reusing its allocation
+    pub fn snapshot(&self, frame: &mut Frame) {
+        let storage = self.storage.load();
+        self.copy_user_layer(&storage, frame);
+        storage.layers.composite_all(&mut frame.pixels);
+    }
+
+    /// Copy only the user layer into `frame`, without background and overlay
+    pub fn snapshot_user_layer(&self, frame: &mut Frame) {
+        self.copy_user_layer(&self.storage.load(), frame);
+    }
+
+    fn copy_user_layer(&self, storage: &Storage<u32>, frame: &mut Frame) {
+        frame.size_x = storage.size_x;
+        frame.size_y = storage.size_y;
+        frame.scale = self.scale;
+        frame.pixels.clear();
+        frame.pixels.extend(
+            storage
+                .cells
+                .iter()
+                .map(|cell| cell.load(Ordering::Relaxed)),
+        );
+        fence(Ordering::Acquire);
+        frame.generation = self.generation();
+    }
+
+    /// Snapshot the canvas, retrying up to `attempts` times until no batch of writes was in
+    /// progress at any point of the copy. Returns whether such a copy was made, `frame` holds
+    /// the last attempt either way.
+    pub fn snapshot_at_boundary(&self, frame: &mut Frame, attempts: usize) -> bool {
+        let mut at_boundary = false;
+        let mut storage = None;
+        for _ in 0..attempts {
+            let settled = self.settled();
+            let before = self.generation();
+            let copied = storage.insert(self.storage.load_full());
+            self.copy_user_layer(copied, frame);
+            // a batch running through the whole copy keeps the generation, so check both
+            if settled && self.settled() && self.generation() == before {
+                at_boundary = true;
+                break;
+            }
         }
-        let mut jpgbuf = self.jpgbuf.write().expect("Could not get write RWlock");
-        jpgbuf.clear();
-        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut *jpgbuf, 50);
-        let subimage = self.view(0, 0, self.width(), self.height()).to_image();
-        match subimage.write_with_encoder(encoder) {
-            Ok(_) => {}
-            Err(err) => tracing::error!("Error writing jpeg buffer: {:?}", err),
+        // composite with the layers of the storage that was copied, a resize may have swapped it
+        if let Some(storage) = storage {
+            storage.layers.composite_all(&mut frame.pixels);
         }
+        at_boundary
+    }
+
+    /// Check whether `frame` differs from the frame this was last called with
+    pub fn check_changed(&self, frame: &Frame) -> bool {
+        let hash = frame.content_hash();
+        self.last_hash.swap(hash, Ordering::Relaxed) != hash
+    }
+
+    /// Run `f` on the snapshot the latest jpeg was encoded from, so other encoders can share it
+    pub fn with_frame<R>(&self, f: impl FnOnce(&Frame) -> R) -> R {
+        f(&self.frame.lock().expect("Could not lock frame"))
+    }
+
+    /// Encode a new jpeg if the canvas changed, returns whether it did.
+    ///
+    /// This is CPU heavy, so it should not be called from the async runtime
+    pub fn update_jpg_buffer(&self) -> bool {
+        let mut spare = self.spare.lock().expect("Could not lock spare frame");
+        self.snapshot(&mut spare);
+        if !self.check_changed(&spare) {
+            return false;
+        }
+        let mut jpgbuf = Vec::new();
+        if let Err(err) = spare.encode_jpg(50, &mut jpgbuf) {
+            tracing::error!("Error writing jpeg buffer: {:?}", err);
+            return false;
+        }
+        // views crop the frame, they only wait for the swap and not for the encode
+        std::mem::swap(
+            &mut *self.frame.lock().expect("Could not lock frame"),
+            &mut spare,
+        );
+        self.jpg.send_replace(jpgbuf.into());
+        true
     }
 }
 
 #[cfg(test)]
 #[allow(clippy::needless_return)]
 mod tests {
-    use super::Flut;
+    use std::{sync::Arc, thread};
+
     use super::Grid;
+    use super::{Anchor, Cell, Flut, Frame, ImageFit, ResizeMode};
+    use crate::Coordinate;
+
+    fn cells(grid: &Flut<u32>) -> Vec<u32> {
+        grid.storage.load().cells.iter().map(u32::load).collect()
+    }
 
     #[tokio::test]
     async fn test_grid_init_values() {
         let grid = Flut::init(3, 3, 0);
 
-        assert_eq!(
-            grid.cells.into_inner(),
-            vec![0, 0, 0, 0, 0, 0, 0, 0, 0].into()
-        );
+        assert_eq!(cells(&grid), vec![0, 0, 0, 0, 0, 0, 0, 0, 0]);
     }
 
     #[tokio::test]
     async fn test_grid_init_size() {
-        let grid = Flut::init(800, 600, 0);
+        let grid = Flut::init(800, 600, 0u32);
 
-        assert_eq!(grid.size_x, 800);
-        assert_eq!(grid.size_y, 600);
+        assert_eq!(grid.get_size(), (800, 600));
     }
 
     #[tokio::test]
@@ -147,10 +848,7 @@ mod tests {
         let grid = Flut::init(3, 3, 0);
         grid.set(1, 1, 255);
         grid.set(2, 1, 256);
-        assert_eq!(
-            grid.cells.into_inner(),
-            vec![0, 0, 0, 0, 255, 256, 0, 0, 0].into()
-        );
+        assert_eq!(cells(&grid), vec![0, 0, 0, 0, 255, 256, 0, 0, 0]);
     }
 
     #[tokio::test]
@@ -158,17 +856,14 @@ mod tests {
         let grid = Flut::init(3, 3, 0);
         grid.set(1, 1, 255);
         grid.set(3, 1, 256);
-        assert_eq!(
-            grid.cells.into_inner(),
-            vec![0, 0, 0, 0, 255, 0, 0, 0, 0].into()
-        );
+        assert_eq!(cells(&grid), vec![0, 0, 0, 0, 255, 0, 0, 0, 0]);
     }
 
     #[tokio::test]
     async fn test_grid_get() {
         let grid = Flut::init(3, 3, 0);
         grid.set(1, 2, 222);
-        assert_eq!(grid.get(1, 2), Some(&222));
+        assert_eq!(grid.get(1, 2), Some(222));
     }
 
     #[tokio::test]
@@ -176,6 +871,386 @@ mod tests {
         let grid = Flut::init(3, 3, 0);
         grid.set(3, 1, 256);
         assert_eq!(grid.get(3, 1), None);
-        assert_eq!(grid.get(1, 2), Some(&0));
+        assert_eq!(grid.get(1, 2), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_grid_check_changed() {
+        let grid = Flut::init(3, 3, 0);
+        let mut frame = Frame::new();
+        grid.snapshot(&mut frame);
+        assert!(grid.check_changed(&frame));
+        assert!(!grid.check_changed(&frame));
+        grid.set(1, 1, 255);
+        grid.snapshot(&mut frame);
+        assert!(grid.check_changed(&frame));
+        assert!(!grid.check_changed(&frame));
+    }
+
+    #[tokio::test]
+    async fn test_grid_snapshot() {
+        let grid = Flut::init(3, 2, 0);
+        grid.set(2, 1, 0x12_34_56_ff);
+        grid.next_generation();
+        let mut frame = Frame::new();
+        grid.snapshot(&mut frame);
+        assert_eq!(frame.get_size(), (3, 2));
+        assert_eq!(frame.generation(), 1);
+        assert_eq!(frame.pixels(), &[0, 0, 0, 0, 0, 0x12_34_56_ff]);
+        assert_eq!(
+            frame.to_rgb_bytes(),
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56]
+        );
+
+        // the frame keeps its allocation and doesn't see later writes
+        let capacity = frame.pixels.capacity();
+        grid.set(0, 0, 1);
+        assert_eq!(frame.pixels()[0], 0);
+        grid.snapshot(&mut frame);
+        assert_eq!(frame.pixels()[0], 1);
+        assert_eq!(frame.pixels.capacity(), capacity);
+    }
+
+    #[tokio::test]
+    async fn test_grid_subscribe_jpg() {
+        let grid = Flut::init(3, 3, 0);
+        let mut jpg = grid.subscribe_jpg();
+        assert!(jpg.borrow_and_update().is_empty());
+        assert!(grid.update_jpg_buffer());
+        assert!(jpg.has_changed().unwrap());
+        assert_eq!(*jpg.borrow_and_update(), grid.read_jpg_buffer());
+        // nothing changed, so nothing is published
+        assert!(!grid.update_jpg_buffer());
+        assert!(!jpg.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_grid_snapshot_at_boundary() {
+        let grid = Flut::init(3, 3, 0);
+        let mut frame = Frame::new();
+        assert!(grid.snapshot_at_boundary(&mut frame, 1));
+        assert!(!grid.snapshot_at_boundary(&mut frame, 0));
+
+        // a batch that is still running when the copy ends isn't a boundary
+        let batch = grid.begin_batch();
+        assert!(grid.set_in(&batch, 1, 1, 0xff, 0));
+        assert!(!grid.snapshot_at_boundary(&mut frame, 3));
+        grid.end_batch(batch);
+        assert_eq!(frame.pixels()[4], 0xff);
+        assert!(grid.snapshot_at_boundary(&mut frame, 1));
+        assert_eq!(grid.generation(), 1);
+    }
+
+    #[test]
+    fn test_grid_concurrent_set_get_encode() {
+        let grid = Arc::new(Flut::init(64, 64, 0u32));
+        let writers: Vec<_> = (1..=4u32)
+            .map(|id| {
+                let grid = grid.clone();
+                thread::spawn(move || {
+                    for round in 0..50u32 {
+                        for y in 0..64 {
+                            for x in 0..64 {
+                                grid.set(
+                                    x,
+                                    y,
+                                    (id << 24) | (round << 12) | (y as u32 * 64 + x as u32),
+                                );
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let grid = grid.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    for y in 0..64 {
+                        for x in 0..64 {
+                            let value = grid.get(x, y).unwrap();
+                            // every value is either the initial one or was written for this cell
+                            assert!(value == 0 || value & 0xfff == y as u32 * 64 + x as u32);
+                        }
+                    }
+                }
+            })
+        };
+        let encoder = {
+            let grid = grid.clone();
+            thread::spawn(move || {
+                let mut frame = Frame::new();
+                for _ in 0..20 {
+                    grid.update_jpg_buffer();
+                    grid.snapshot(&mut frame);
+                    for (idx, value) in frame.pixels().iter().enumerate() {
+                        assert!(*value == 0 || *value & 0xfff == idx as u32);
+                    }
+                }
+            })
+        };
+        for handle in writers {
+            handle.join().unwrap();
+        }
+        reader.join().unwrap();
+        encoder.join().unwrap();
+        grid.update_jpg_buffer();
+        assert!(!grid.read_jpg_buffer().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_grid_layers() {
+        let grid = Flut::init(2, 1, 0);
+        grid.set(1, 0, 0x00_ff_00_ff);
+        let background = image::RgbaImage::from_pixel(2, 1, image::Rgba([0xff, 0, 0, 0xff]));
+        grid.set_background(Some(&image::DynamicImage::ImageRgba8(background)));
+
+        let mut frame = Frame::new();
+        grid.snapshot(&mut frame);
+        assert_eq!(frame.pixels(), &[0xff_00_00_ff, 0x00_ff_00_ff]);
+        grid.snapshot_user_layer(&mut frame);
+        assert_eq!(frame.pixels(), &[0, 0x00_ff_00_ff]);
+
+        assert_eq!(grid.read(0, 0), Some(0));
+        grid.set_read_composite(true);
+        assert_eq!(grid.read(0, 0), Some(0xff_00_00_ff));
+        assert_eq!(grid.read(2, 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_grid_initial_image() {
+        let image = image::RgbaImage::from_pixel(1, 1, image::Rgba([0x12, 0x34, 0x56, 0xff]));
+        let grid = Flut::init(3, 1, 0xff_00_ff_ff)
+            .with_initial_image(&image::DynamicImage::ImageRgba8(image), ImageFit::Center);
+        assert_eq!(grid.get(0, 0), Some(0xff_00_ff_ff));
+        assert_eq!(grid.get(1, 0), Some(0x12_34_56_ff));
+
+        grid.set(0, 0, 0);
+        grid.set(1, 0, 0);
+        grid.reset_region(1, 0, 5, 5);
+        assert_eq!(grid.get(0, 0), Some(0));
+        assert_eq!(grid.get(1, 0), Some(0x12_34_56_ff));
+        grid.reset_region(0, 0, 1, 1);
+        assert_eq!(grid.get(0, 0), Some(0xff_00_ff_ff));
+    }
+
+    #[test]
+    fn test_grid_restore() {
+        let grid = Flut::init(2, 2, 0);
+        grid.restore(&Frame::from_pixels(3, 1, vec![1, 2, 3]))
+            .unwrap();
+        assert_eq!(grid.get_size(), (3, 1));
+        assert_eq!(cells(&grid), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_grid_resize_anchor() {
+        let grid = Flut::init(2, 2, 0).with_activity();
+        grid.set(0, 0, 1);
+        grid.set(1, 1, 2);
+        grid.resize(3, 3, ResizeMode::Anchor(Anchor::BottomRight))
+            .unwrap();
+        assert_eq!(grid.get_size(), (3, 3));
+        assert_eq!(cells(&grid), vec![0, 0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(grid.activity().unwrap().writes(8), 0);
+
+        grid.resize(1, 1, ResizeMode::Anchor(Anchor::TopLeft))
+            .unwrap();
+        assert_eq!(cells(&grid), vec![0]);
+        assert_eq!(grid.get(1, 0), None);
+        grid.resize(2, 1, ResizeMode::Anchor(Anchor::Center))
+            .unwrap();
+        assert_eq!(cells(&grid), vec![0, 0]);
+
+        assert!(grid.resize(0, 1, ResizeMode::Scale).is_err());
+        assert!(grid.resize(1 << 16, 1, ResizeMode::Scale).is_err());
+        let side = Coordinate::MAX as usize;
+        assert!(grid.resize(side, side, ResizeMode::Scale).is_err());
+        assert_eq!(grid.get_size(), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_grid_resize_scale_and_layers() {
+        let grid = Flut::init(1, 1, 0x12_34_56_ff);
+        let background = image::RgbaImage::from_pixel(1, 1, image::Rgba([0xff, 0, 0, 0xff]));
+        grid.set_background(Some(&image::DynamicImage::ImageRgba8(background)));
+        grid.resize(2, 2, ResizeMode::Scale).unwrap();
+        assert_eq!(cells(&grid), vec![0x12_34_56_ff; 4]);
+
+        grid.set(1, 1, 0);
+        let mut frame = Frame::new();
+        grid.snapshot(&mut frame);
+        assert_eq!(frame.get_size(), (2, 2));
+        assert_eq!(frame.pixels()[3], 0xff_00_00_ff);
+    }
+
+    #[test]
+    fn test_grid_resize_while_writing() {
+        let grid = Arc::new(Flut::init(64, 64, 0u32));
+        let writer = {
+            let grid = grid.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    for y in 0..64 {
+                        for x in 0..64 {
+                            grid.set_by(x, y, 1, 0);
+                        }
+                    }
+                }
+            })
+        };
+        let mut frame = Frame::new();
+        for round in 0..20 {
+            let size = 32 + round * 4;
+            grid.resize(size, size, ResizeMode::Anchor(Anchor::Center))
+                .unwrap();
+            grid.snapshot_at_boundary(&mut frame, 2);
+            assert_eq!(frame.pixels().len(), size * size);
+        }
+        writer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_grid_scale() {
+        let grid = Flut::init(2, 1, 0).with_scale(3);
+        grid.set(1, 0, 0x12_34_56_ff);
+        let mut frame = Frame::new();
+        grid.snapshot(&mut frame);
+        assert_eq!(frame.get_size(), (2, 1));
+        let image = frame.to_rgb_image();
+        assert_eq!(image.dimensions(), (6, 3));
+        assert_eq!(image.get_pixel(2, 2).0, [0, 0, 0]);
+        assert_eq!(image.get_pixel(3, 0).0, [0x12, 0x34, 0x56]);
+        assert_eq!(image.get_pixel(5, 2).0, [0x12, 0x34, 0x56]);
+        assert_eq!(Flut::init(1, 1, 0).with_scale(0).scale(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_frame_crop() {
+        let grid = Flut::init(3, 3, 0).with_scale(2);
+        grid.set(2, 1, 5);
+        grid.update_jpg_buffer();
+        let crop = grid.with_frame(|frame| frame.crop(1, 1, 5, 1, 3));
+        assert_eq!(crop.get_size(), (2, 1));
+        assert_eq!(crop.pixels(), &[0, 5]);
+        assert_eq!(crop.scale(), 6);
+        assert_eq!(crop.to_rgb_image().dimensions(), (12, 6));
+        let crop = grid.with_frame(|frame| frame.crop(3, 0, 1, 1, 1));
+        assert_eq!(crop.get_size(), (0, 1));
+    }
+}
+
+/// `cargo bench` compares the atomic cells against the unsafe cell the canvas used before
+#[cfg(test)]
+mod benches {
+    extern crate test;
+
+    use std::cell::UnsafeCell;
+
+    use test::{black_box, Bencher};
+
+    use super::{Flut, Grid};
+    use crate::Coordinate;
+
+    const SIZE_X: Coordinate = 800;
+    const SIZE_Y: Coordinate = 600;
+
+    /// The canvas as it was before, a plain slice written through an unsafe cell
+    struct Baseline {
+        size_x: usize,
+        size_y: usize,
+        cells: UnsafeCell<Box<[u32]>>,
+    }
+
+    impl Baseline {
+        fn init(size_x: usize, size_y: usize, value: u32) -> Baseline {
+            Baseline {
+                size_x,
+                size_y,
+                cells: UnsafeCell::new(vec![value; size_x * size_y].into_boxed_slice()),
+            }
+        }
+
+        fn index(&self, x: Coordinate, y: Coordinate) -> Option<usize> {
+            let (x, y) = (x as usize, y as usize);
+            (x < self.size_x && y < self.size_y).then_some(y * self.size_x + x)
+        }
+
+        fn get(&self, x: Coordinate, y: Coordinate) -> Option<u32> {
+            self.index(x, y)
+                .map(|idx| unsafe { (&(*self.cells.get()))[idx] })
+        }
+
+        fn set(&self, x: Coordinate, y: Coordinate, value: u32) {
+            if let Some(idx) = self.index(x, y) {
+                unsafe { (&mut (*self.cells.get()))[idx] = value }
+            }
+        }
+    }
+
+    fn pixels() -> impl Iterator<Item = (Coordinate, Coordinate)> {
+        (0..SIZE_Y).flat_map(|y| (0..SIZE_X).map(move |x| (x, y)))
+    }
+
+    #[bench]
+    fn bench_set_baseline(b: &mut Bencher) {
+        let grid = Baseline::init(SIZE_X as usize, SIZE_Y as usize, 0);
+        b.iter(|| {
+            for (x, y) in pixels() {
+                grid.set(black_box(x), black_box(y), 0xff_00_ff_ff);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_set(b: &mut Bencher) {
+        let grid = Flut::init(SIZE_X as usize, SIZE_Y as usize, 0);
+        b.iter(|| {
+            for (x, y) in pixels() {
+                grid.set(black_box(x), black_box(y), 0xff_00_ff_ff);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_set_by(b: &mut Bencher) {
+        let grid = Flut::init(SIZE_X as usize, SIZE_Y as usize, 0);
+        b.iter(|| {
+            for (x, y) in pixels() {
+                grid.set_by(black_box(x), black_box(y), 0xff_00_ff_ff, 1);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_set_in_batch(b: &mut Bencher) {
+        let grid = Flut::init(SIZE_X as usize, SIZE_Y as usize, 0);
+        b.iter(|| {
+            let batch = grid.begin_batch();
+            for (x, y) in pixels() {
+                grid.set_in(&batch, black_box(x), black_box(y), 0xff_00_ff_ff, 1);
+            }
+            grid.end_batch(batch);
+        });
+    }
+
+    #[bench]
+    fn bench_get_baseline(b: &mut Bencher) {
+        let grid = Baseline::init(SIZE_X as usize, SIZE_Y as usize, 0);
+        b.iter(|| {
+            for (x, y) in pixels() {
+                black_box(grid.get(black_box(x), black_box(y)));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_get(b: &mut Bencher) {
+        let grid = Flut::init(SIZE_X as usize, SIZE_Y as usize, 0);
+        b.iter(|| {
+            for (x, y) in pixels() {
+                black_box(grid.get(black_box(x), black_box(y)));
+            }
+        });
     }
 }
@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use image::{imageops::FilterType, DynamicImage};
+
+/// The layers around the user writable cells of a canvas. The background shows through where
+/// the user layer is transparent, the overlay is drawn on top of both and never read by clients
+/// unless they read the composite. Both are rendered for one canvas size.
+///
+/// Both are swapped together, so compositing takes a single lock free load however many pixels
+/// it covers.
+#[derive(Default)]
+pub(crate) struct Layers {
+    state: ArcSwap<LayerState>,
+}
+
+#[derive(Clone, Default)]
+struct LayerState {
+    background: Option<Layer>,
+    overlay: Option<Layer>,
+}
+
+/// A full canvas of `0xRRGGBBAA` pixels, row by row
+type Layer = Arc<[u32]>;
+
+/// Draw `over` on top of `under` weighted by the alpha of `over`, the result is opaque
+#[inline]
+pub(crate) fn blend(under: u32, over: u32) -> u32 {
+    let alpha = over & 0xff;
+    let channel = |shift: u32| {
+        let under = (under >> shift) & 0xff;
+        let over = (over >> shift) & 0xff;
+        ((over * alpha + under * (255 - alpha)) / 255) << shift
+    };
+    channel(24) | channel(16) | channel(8) | 0xff
+}
+
+/// How an image that doesn't match the size of a canvas is put on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFit {
+    /// Stretch the image to the canvas
+    Scale,
+    /// Keep the size of the image and put it in the middle of the canvas
+    Center,
+    /// Repeat the image from the top left corner
+    Tile,
+}
+
+/// Put `image` on a `size_x` by `size_y` canvas as `0xRRGGBBAA` pixels, row by row. Pixels the
+/// image doesn't cover are `fill`.
+pub(crate) fn render_image(
+    image: &DynamicImage,
+    size_x: usize,
+    size_y: usize,
+    fit: ImageFit,
+    fill: u32,
+) -> Vec<u32> {
+    let image = match fit {
+        ImageFit::Scale => image
+            .resize_exact(size_x as u32, size_y as u32, FilterType::Triangle)
+            .to_rgba8(),
+        ImageFit::Center | ImageFit::Tile => image.to_rgba8(),
+    };
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut pixels = vec![fill; size_x * size_y];
+    if width == 0 || height == 0 {
+        return pixels;
+    }
+    let (offset_x, offset_y) = match fit {
+        ImageFit::Center => (
+            (size_x as isize - width as isize) / 2,
+            (size_y as isize - height as isize) / 2,
+        ),
+        ImageFit::Scale | ImageFit::Tile => (0, 0),
+    };
+    for y in 0..size_y {
+        for x in 0..size_x {
+            let (ix, iy) = match fit {
+                ImageFit::Tile => (x % width, y % height),
+                ImageFit::Scale | ImageFit::Center => {
+                    let ix = x as isize - offset_x;
+                    let iy = y as isize - offset_y;
+                    if ix < 0 || iy < 0 || ix >= width as isize || iy >= height as isize {
+                        continue;
+                    }
+                    (ix as usize, iy as usize)
+                }
+            };
+            pixels[y * size_x + x] = u32::from_be_bytes(image.get_pixel(ix as u32, iy as u32).0);
+        }
+    }
+    pixels
+}
+
+/// Scale `image` to the canvas
+fn to_layer(image: &DynamicImage, size_x: usize, size_y: usize) -> Layer {
+    render_image(image, size_x, size_y, ImageFit::Scale, 0).into()
+}
+
+impl LayerState {
+    #[inline]
+    fn composite_pixel(&self, idx: usize, user: u32) -> u32 {
+        let pixel = match &self.background {
+            Some(background) => blend(background[idx], user),
+            None => user | 0xff,
+        };
+        match &self.overlay {
+            Some(overlay) => blend(pixel, overlay[idx]),
+            None => pixel,
+        }
+    }
+}
+
+impl Layers {
+    pub(crate) fn set_background(
+        &self,
+        image: Option<&DynamicImage>,
+        size_x: usize,
+        size_y: usize,
+    ) {
+        let background = image.map(|image| to_layer(image, size_x, size_y));
+        self.state.rcu(|state| LayerState {
+            background: background.clone(),
+            ..LayerState::clone(state)
+        });
+    }
+
+    pub(crate) fn set_overlay(&self, image: Option<&DynamicImage>, size_x: usize, size_y: usize) {
+        let overlay = image.map(|image| to_layer(image, size_x, size_y));
+        self.state.rcu(|state| LayerState {
+            overlay: overlay.clone(),
+            ..LayerState::clone(state)
+        });
+    }
+
+    /// The pixel at `idx` as it is shown, `user` is the pixel of the user layer
+    pub(crate) fn composite(&self, idx: usize, user: u32) -> u32 {
+        self.state.load().composite_pixel(idx, user)
+    }
+
+    /// Turn a copy of the user layer into what is shown, leaves it alone without extra layers
+    pub(crate) fn composite_all(&self, pixels: &mut [u32]) {
+        let state = self.state.load();
+        if state.background.is_none() && state.overlay.is_none() {
+            return;
+        }
+        for (idx, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = state.composite_pixel(idx, *pixel);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn test_blend() {
+        assert_eq!(blend(0x11_22_33_ff, 0xaa_bb_cc_ff), 0xaa_bb_cc_ff);
+        assert_eq!(blend(0x11_22_33_ff, 0xaa_bb_cc_00), 0x11_22_33_ff);
+        assert_eq!(blend(0x00_00_00_ff, 0xff_ff_ff_80), 0x80_80_80_ff);
+    }
+
+    #[test]
+    fn test_composite() {
+        let layers = Layers::default();
+        let mut pixels = [0x12_34_56_00, 0x12_34_56_ff];
+        layers.composite_all(&mut pixels);
+        assert_eq!(pixels, [0x12_34_56_00, 0x12_34_56_ff]);
+
+        let background = RgbaImage::from_pixel(2, 1, Rgba([0xff, 0, 0, 0xff]));
+        layers.set_background(Some(&DynamicImage::ImageRgba8(background)), 2, 1);
+        let mut overlay = RgbaImage::new(2, 1);
+        overlay.put_pixel(1, 0, Rgba([0, 0, 0xff, 0xff]));
+        layers.set_overlay(Some(&DynamicImage::ImageRgba8(overlay)), 2, 1);
+        layers.composite_all(&mut pixels);
+        assert_eq!(pixels, [0xff_00_00_ff, 0x00_00_ff_ff]);
+        assert_eq!(layers.composite(0, 0x00_ff_00_ff), 0x00_ff_00_ff);
+    }
+
+    #[test]
+    fn test_render_image() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([1, 2, 3, 4]));
+        image.put_pixel(1, 0, Rgba([5, 6, 7, 8]));
+        let image = DynamicImage::ImageRgba8(image);
+        let (a, b) = (0x01_02_03_04, 0x05_06_07_08);
+        assert_eq!(
+            render_image(&image, 4, 1, ImageFit::Tile, 9),
+            vec![a, b, a, b]
+        );
+        assert_eq!(
+            render_image(&image, 4, 2, ImageFit::Center, 9),
+            vec![9, a, b, 9, 9, 9, 9, 9]
+        );
+        assert_eq!(render_image(&image, 1, 1, ImageFit::Center, 9), vec![a]);
+        assert_eq!(render_image(&image, 2, 1, ImageFit::Scale, 9), vec![a, b]);
+    }
+}
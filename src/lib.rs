@@ -1,50 +1,64 @@
-#![feature(test)]
-#![feature(sync_unsafe_cell)]
-#![feature(if_let_guard)]
+#![cfg_attr(test, feature(test))]
 
-use std::sync::atomic::AtomicU64;
+use std::{borrow::Cow, sync::atomic::AtomicU64};
 
+pub use activity::Activity;
+pub use attribution::Attribution;
 pub use color::Color;
-use grid::Grid;
+pub use server::{Server, ServerHandle};
 
+pub mod archive;
+pub mod clients;
 pub mod config;
 pub mod flutclient;
 pub mod grid;
+pub mod protection;
 pub mod protocols;
+pub mod recordings;
+pub mod stats;
 pub(crate) mod stream;
 pub mod utils;
+pub mod video;
+pub mod wall;
 pub mod webapi;
 
+mod activity;
+mod attribution;
+mod clock;
 mod color;
+mod encoder;
+mod layers;
+mod server;
+mod views;
 
 pub type Canvas = u8;
 pub type Coordinate = u16;
 
 pub static COUNTER: AtomicU64 = AtomicU64::new(0);
 pub static CLIENTS: AtomicU64 = AtomicU64::new(0);
+/// Bytes read from pixelflut clients
+pub static BYTES: AtomicU64 = AtomicU64::new(0);
+/// Commands that could not be parsed
+pub static PARSE_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Pixels that were not set because they are protected
+pub static REJECTED: AtomicU64 = AtomicU64::new(0);
+/// How long the last jpeg encode took
+pub static ENCODE_MICROS: AtomicU64 = AtomicU64::new(0);
+/// The longest a task recently had to wait to be scheduled on the async runtime
+pub static RUNTIME_LAG_MICROS: AtomicU64 = AtomicU64::new(0);
+/// How many bytes the recorded frames take on disk
+pub static RECORDING_BYTES: AtomicU64 = AtomicU64::new(0);
 
 pub type AsyncResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-fn set_pixel_rgba(
-    grids: &[grid::Flut<u32>],
-    canvas: Canvas,
-    x: Coordinate,
-    y: Coordinate,
-    rgb: u32,
-) {
-    if let Some(grid) = grids.get(canvas as usize) {
-        grid.set(x, y, rgb);
-    }
-}
-
 fn get_pixel(
     grids: &[grid::Flut<u32>],
     canvas: Canvas,
     x: Coordinate,
     y: Coordinate,
-) -> Option<&u32> {
+) -> Option<u32> {
     match grids.get(canvas as usize) {
-        Some(grid) => grid.get(x, y),
+        Some(grid) => grid.read(x, y),
         None => None,
     }
 }
@@ -54,19 +68,22 @@ fn increment_counter(amount: u64) {
     COUNTER.fetch_add(amount, std::sync::atomic::Ordering::Relaxed);
 }
 
-#[derive(Debug, PartialEq)]
+/// A protocol and whether it is enabled, names the decoding side doesn't know are owned
+#[derive(Debug, PartialEq, Clone)]
 pub enum ProtocolStatus {
-    Enabled(&'static str),
-    Disabled(&'static str),
+    Enabled(Cow<'static, str>),
+    Disabled(Cow<'static, str>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Protocol {
     Text,
     Binary,
+    /// A protocol added with [`protocols::register_protocol`]
+    Custom(&'static str),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Help,
     Protocols,
@@ -75,9 +92,11 @@ pub enum Command {
     SetPixel(Canvas, Coordinate, Coordinate, Color),
     ChangeCanvas(Canvas),
     ChangeProtocol(Protocol),
+    /// Name the connection, admins see it next to the pixels it wrote
+    Name(String),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Response {
     Help,
     Protocols(Vec<ProtocolStatus>),
@@ -1,82 +1,19 @@
-use std::{
-    fs::{create_dir_all, File},
-    io::Write as _,
-    path::Path,
-    process::exit,
-    sync::Arc,
-    time::Duration,
-};
+use std::process::exit;
 
 use flurry::{
-    config::{GRID_LENGTH, HOST, IMAGE_SAVE_INTERVAL, JPEG_UPDATE_INTERVAL},
-    flutclient::{FlutClient, ParserTypes},
-    grid::{self, Flut},
-    webapi::WebApiContext,
-    AsyncResult, CLIENTS,
+    config::{
+        video_sinks, ADMIN_TOKEN, ARCHIVE_INTERVAL, BACKGROUND_IMAGE, CANVAS_SCALE,
+        FORCED_PROTOCOL_HOSTS, GRID_LENGTH, HOST, IMAGE_SAVE_INTERVAL, INITIAL_IMAGE,
+        INITIAL_IMAGE_FIT, JPEG_UPDATE_INTERVAL, OVERLAY_IMAGE, READ_COMPOSITE,
+        RECORDING_RETENTION, TRACK_ACTIVITY, TRACK_ATTRIBUTION, WALLS, WEB_HOST,
+    },
+    flutclient::ParserTypes,
+    grid::Flut,
+    Server,
 };
-use futures::never::Never;
-use tokio::{net::TcpListener, time::interval, try_join};
+use image::DynamicImage;
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
-/// This function starts a timer that saves the current grid state every `duration`.
-/// These images may then be used for moderation or timelapses
-///
-/// # Errors
-///
-/// This function will return an error if it is unable to create or write to the file for the image
-async fn save_image_frames(
-    grids: Arc<[grid::Flut<u32>; GRID_LENGTH]>,
-    duration: Duration,
-) -> AsyncResult<Never> {
-    let mut timer = interval(duration);
-    let base_dir = Path::new("./recordings");
-    create_dir_all(base_dir)?;
-    loop {
-        timer.tick().await;
-        for grid in grids.as_ref() {
-            let p = base_dir.join(format!(
-                "{}",
-                chrono::Local::now().format("%Y-%m-%d_%H-%M-%S.jpg")
-            ));
-            let mut file_writer = File::create(p)?;
-
-            file_writer.write_all(&grid.read_jpg_buffer())?;
-        }
-    }
-}
-
-/// Handle connections made to the socket, keeps a vec of the currently active connections,
-/// uses timeout to loop through them and clean them up to stop a memory leak while not throwing
-/// everything away
-async fn handle_flut(
-    flut_listener: TcpListener,
-    grids: Arc<[grid::Flut<u32>]>,
-) -> AsyncResult<Never> {
-    let mut handles = Vec::new();
-    loop {
-        let (mut socket, _) = flut_listener.accept().await?;
-        let grids = grids.clone();
-        handles.push(tokio::spawn(async move {
-            let (reader, writer) = socket.split();
-            let mut connection = FlutClient::new(reader, writer, grids);
-            CLIENTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            let resp = connection.process_socket().await;
-            CLIENTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-            resp
-        }))
-    }
-}
-
-async fn jpeg_update_loop(grids: Arc<[Flut<u32>]>) -> AsyncResult<Never> {
-    let mut interval = interval(JPEG_UPDATE_INTERVAL);
-    loop {
-        interval.tick().await;
-        for grid in grids.as_ref() {
-            grid.update_jpg_buffer();
-        }
-    }
-}
-
 #[tokio::main]
 #[allow(clippy::needless_return)]
 async fn main() {
@@ -90,31 +27,76 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let grids: Arc<[Flut<u32>; GRID_LENGTH]> = [grid::Flut::init(800, 600, 0xff_00_ff_ff)].into();
+    let mut server = Server::new()
+        .flut_host(HOST)
+        .web_host(WEB_HOST)
+        .recordings("./recordings", IMAGE_SAVE_INTERVAL)
+        .recording_retention(RECORDING_RETENTION)
+        .jpeg_interval(JPEG_UPDATE_INTERVAL);
+    let background = BACKGROUND_IMAGE.map(load_image);
+    let overlay = OVERLAY_IMAGE.map(load_image);
+    let initial = INITIAL_IMAGE.map(load_image);
+    // with a background the user layer starts out transparent so the background shows
+    let fill = match background {
+        Some(_) => 0,
+        None => 0xff_00_ff_ff,
+    };
+    for _ in 0..GRID_LENGTH {
+        let mut grid =
+            Flut::init(800 / CANVAS_SCALE, 600 / CANVAS_SCALE, fill).with_scale(CANVAS_SCALE);
+        if let Some(initial) = &initial {
+            grid = grid.with_initial_image(initial, INITIAL_IMAGE_FIT);
+        }
+        grid.set_background(background.as_ref());
+        grid.set_overlay(overlay.as_ref());
+        grid.set_read_composite(READ_COMPOSITE);
+        if TRACK_ACTIVITY {
+            grid = grid.with_activity();
+        }
+        if TRACK_ATTRIBUTION {
+            grid = grid.with_attribution();
+        }
+        server = server.canvas(grid);
+    }
+    for tiles in WALLS {
+        server = server.wall(tiles);
+    }
+    for sink in video_sinks() {
+        server = server.video_sink(sink);
+    }
+    if let Some(interval) = ARCHIVE_INTERVAL {
+        server = server.archives("./archives", interval);
+    }
+    if let Some(token) = ADMIN_TOKEN {
+        server = server.admin_token(token);
+    }
+    for (host, protocol) in FORCED_PROTOCOL_HOSTS {
+        server = server.forced_protocol_host(*host, *protocol);
+    }
     tracing::trace!("created grids");
 
     ParserTypes::announce();
 
-    let Ok(flut_listener) = TcpListener::bind(HOST).await else {
-        tracing::error!(
-            "Was unable to bind to {HOST}, please check if a different process is bound"
-        );
-        exit(1);
+    let server = match server.start().await {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::error!("Could not start the server: {err}");
+            exit(1);
+        }
     };
-    tracing::info!("Started TCP listener on {HOST}");
 
-    let snapshots = tokio::spawn(save_image_frames(grids.clone(), IMAGE_SAVE_INTERVAL));
-    let pixelflut_server = tokio::spawn(handle_flut(flut_listener, grids.clone()));
-    let jpeg_update_loop = tokio::spawn(jpeg_update_loop(grids.clone()));
-    let website = tokio::spawn(flurry::webapi::serve(WebApiContext {
-        grids: grids.clone(),
-    }));
+    if let Err(err) = server.wait().await {
+        tracing::error!("The server stopped: {err}");
+        exit(1);
+    }
+}
 
-    let res = try_join! {
-        snapshots,
-        pixelflut_server,
-        jpeg_update_loop,
-        website,
-    };
-    tracing::error!("something went wrong {:?}", res);
+fn load_image(path: &str) -> DynamicImage {
+    match image::open(path) {
+        Ok(image) => image,
+        Err(err) => {
+            tracing::error!("Could not load {path}: {err}");
+            exit(1);
+        }
+    }
 }
@@ -0,0 +1,332 @@
+use std::{
+    io::{self, Error, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use arc_swap::ArcSwap;
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use crate::Coordinate;
+
+/// An area of a canvas pixelflut clients can't write to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProtectedRegion {
+    Rect {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    /// Every opaque, bright pixel of a mask image placed at `x`, `y`
+    Mask {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        #[serde(skip)]
+        mask: Arc<[bool]>,
+    },
+}
+
+impl ProtectedRegion {
+    /// Protect the pixels of `image` that are at least half opaque and half bright
+    pub fn mask(x: usize, y: usize, image: &DynamicImage) -> ProtectedRegion {
+        let (width, height) = image.dimensions();
+        let luma_alpha = image.to_luma_alpha8();
+        ProtectedRegion::Mask {
+            x,
+            y,
+            width: width as usize,
+            height: height as usize,
+            mask: luma_alpha
+                .pixels()
+                .map(|pixel| pixel.0[0] >= 128 && pixel.0[1] >= 128)
+                .collect(),
+        }
+    }
+
+    /// Call `f` with every protected pixel that lies on a `size_x` by `size_y` canvas
+    fn for_each_pixel(&self, size_x: usize, size_y: usize, mut f: impl FnMut(usize, usize)) {
+        match self {
+            ProtectedRegion::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                for py in *y..y.saturating_add(*height).min(size_y) {
+                    for px in *x..x.saturating_add(*width).min(size_x) {
+                        f(px, py);
+                    }
+                }
+            }
+            ProtectedRegion::Mask {
+                x,
+                y,
+                width,
+                height,
+                mask,
+            } => {
+                for my in 0..*height {
+                    for mx in 0..*width {
+                        let (px, py) = (x.saturating_add(mx), y.saturating_add(my));
+                        if mask[my * width + mx] && px < size_x && py < size_y {
+                            f(px, py);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The protected regions of a canvas, kept as one bit per pixel so the set path only pays a
+/// single relaxed load
+pub struct Protection {
+    any: AtomicBool,
+    mask: ArcSwap<Mask>,
+    regions: Mutex<Vec<(u32, ProtectedRegion)>>,
+    next_id: AtomicU32,
+}
+
+/// The protected pixels of every region for one canvas size, replaced as a whole whenever the
+/// regions or the size change
+struct Mask {
+    size_x: usize,
+    size_y: usize,
+    bits: Box<[u64]>,
+}
+
+impl Mask {
+    fn new(size_x: usize, size_y: usize, regions: &[(u32, ProtectedRegion)]) -> Mask {
+        let mut bits = vec![0u64; (size_x * size_y).div_ceil(64)];
+        for (_, region) in regions {
+            region.for_each_pixel(size_x, size_y, |x, y| {
+                let idx = y * size_x + x;
+                bits[idx / 64] |= 1 << (idx % 64);
+            });
+        }
+        Mask {
+            size_x,
+            size_y,
+            bits: bits.into_boxed_slice(),
+        }
+    }
+}
+
+impl Protection {
+    pub(crate) fn new(size_x: usize, size_y: usize) -> Protection {
+        Protection {
+            any: AtomicBool::new(false),
+            mask: ArcSwap::from_pointee(Mask::new(size_x, size_y, &[])),
+            regions: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn is_protected(&self, x: usize, y: usize) -> bool {
+        if !self.any.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mask = self.mask.load();
+        let idx = y * mask.size_x + x;
+        x < mask.size_x && y < mask.size_y && mask.bits[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    /// Protect `region`, returns the id to remove it with.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `InvalidInput` if the region is empty, larger than any canvas
+    /// can be or doesn't overlap the canvas, or if a mask doesn't have a value for every pixel
+    pub fn add(&self, region: ProtectedRegion) -> io::Result<u32> {
+        let (x, y, width, height) = match &region {
+            ProtectedRegion::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => (*x, *y, *width, *height),
+            ProtectedRegion::Mask {
+                x,
+                y,
+                width,
+                height,
+                mask,
+            } => {
+                if width.checked_mul(*height) != Some(mask.len()) {
+                    return Err(Error::from(ErrorKind::InvalidInput));
+                }
+                (*x, *y, *width, *height)
+            }
+        };
+        let max = Coordinate::MAX as usize;
+        if width == 0 || height == 0 || width > max || height > max {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let mut regions = self
+            .regions
+            .lock()
+            .expect("Could not lock protected regions");
+        let (size_x, size_y) = self.size();
+        if x >= size_x || y >= size_y {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        regions.push((id, region));
+        self.rebuild(&regions, size_x, size_y);
+        Ok(id)
+    }
+
+    /// Stop protecting the region with `id`, returns whether it existed
+    pub fn remove(&self, id: u32) -> bool {
+        let mut regions = self
+            .regions
+            .lock()
+            .expect("Could not lock protected regions");
+        let before = regions.len();
+        regions.retain(|(known, _)| *known != id);
+        let (size_x, size_y) = self.size();
+        self.rebuild(&regions, size_x, size_y);
+        regions.len() != before
+    }
+
+    /// Every protected region with its id, in the order they were added
+    pub fn regions(&self) -> Vec<(u32, ProtectedRegion)> {
+        self.regions
+            .lock()
+            .expect("Could not lock protected regions")
+            .clone()
+    }
+
+    /// Follow a resize of the canvas, regions keep their position and are clipped to the new size
+    pub(crate) fn resize(&self, size_x: usize, size_y: usize) {
+        let regions = self
+            .regions
+            .lock()
+            .expect("Could not lock protected regions");
+        self.rebuild(&regions, size_x, size_y);
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let mask = self.mask.load();
+        (mask.size_x, mask.size_y)
+    }
+
+    fn rebuild(&self, regions: &[(u32, ProtectedRegion)], size_x: usize, size_y: usize) {
+        self.mask
+            .store(Arc::new(Mask::new(size_x, size_y, regions)));
+        self.any.store(!regions.is_empty(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn test_rect() {
+        let protection = Protection::new(10, 10);
+        assert!(!protection.is_protected(0, 0));
+        let id = protection
+            .add(ProtectedRegion::Rect {
+                x: 8,
+                y: 1,
+                width: 5,
+                height: 2,
+            })
+            .unwrap();
+        assert!(protection.is_protected(8, 1));
+        assert!(protection.is_protected(9, 2));
+        assert!(!protection.is_protected(7, 1));
+        assert!(!protection.is_protected(8, 3));
+        assert!(protection.remove(id));
+        assert!(!protection.is_protected(8, 1));
+        assert!(!protection.remove(id));
+    }
+
+    #[test]
+    fn test_mask_and_bounds() {
+        let protection = Protection::new(4, 4);
+        let mut image = RgbaImage::new(2, 2);
+        image.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+        image.put_pixel(0, 1, Rgba([255, 255, 255, 0]));
+        let region = ProtectedRegion::mask(1, 1, &DynamicImage::ImageRgba8(image));
+        protection.add(region).unwrap();
+        assert!(protection.is_protected(2, 1));
+        assert!(!protection.is_protected(1, 2));
+        let outside = ProtectedRegion::Rect {
+            x: 4,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(
+            protection.add(outside).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_empty_and_oversized() {
+        let protection = Protection::new(4, 4);
+        for (width, height) in [(0, 1), (1, 0), (usize::MAX, 1), (1, 1 << 20)] {
+            let region = ProtectedRegion::Rect {
+                x: 1,
+                y: 1,
+                width,
+                height,
+            };
+            assert_eq!(
+                protection.add(region).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+        // regions reaching past the end of the address space are clipped instead of overflowing
+        let edge = ProtectedRegion::Rect {
+            x: usize::MAX - 1,
+            y: 0,
+            width: 5,
+            height: 1,
+        };
+        edge.for_each_pixel(4, 4, |_, _| panic!("the region is outside the canvas"));
+        let largest = ProtectedRegion::Rect {
+            x: 3,
+            y: 3,
+            width: Coordinate::MAX as usize,
+            height: Coordinate::MAX as usize,
+        };
+        protection.add(largest).unwrap();
+        assert!(protection.is_protected(3, 3));
+        assert!(!protection.is_protected(2, 3));
+    }
+
+    #[test]
+    fn test_resize() {
+        let protection = Protection::new(4, 4);
+        protection
+            .add(ProtectedRegion::Rect {
+                x: 2,
+                y: 2,
+                width: 4,
+                height: 1,
+            })
+            .unwrap();
+        assert!(protection.is_protected(3, 2));
+        assert!(!protection.is_protected(4, 2));
+        protection.resize(8, 8);
+        assert!(protection.is_protected(5, 2));
+        assert!(!protection.is_protected(6, 2));
+        protection.resize(2, 2);
+        assert!(!protection.is_protected(2, 2));
+    }
+}
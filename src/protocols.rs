@@ -1,13 +1,31 @@
 mod binary_protocol;
+pub(crate) mod registry;
 mod text_protocol;
 
-use std::io;
+use std::{
+    future::Future,
+    io::{self, Error, ErrorKind},
+};
 
 pub use binary_protocol::BinaryParser;
+use bytes::BytesMut;
+pub(crate) use registry::{custom_protocol_names, find_custom_protocol};
+pub use registry::{register_protocol, CustomParser, CustomProtocol, CustomProtocolClone};
 pub use text_protocol::TextParser;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
 
-use crate::{Canvas, Command, Response};
+use crate::{flutclient::ParserTypes, Canvas, Command, Protocol, ProtocolStatus, Response};
+
+/// Guess the protocol a client speaks from the first byte it sent.
+///
+/// Every text command starts with one of these uppercase letters, and the binary protocol keeps
+/// them reserved, so anything else has to be binary.
+pub fn detect_protocol(first: u8) -> Protocol {
+    match first {
+        b'H' | b'S' | b'P' | b'C' | b'N' => Protocol::Text,
+        _ => Protocol::Binary,
+    }
+}
 
 pub(crate) trait Parser<R>
 where
@@ -26,3 +44,94 @@ where
 {
     async fn unparse(&self, response: Response, writer: &mut W) -> io::Result<()>;
 }
+
+/// The client side counterpart of [`Parser`], writes a command in the same format the server
+/// parses it in.
+pub trait CommandEncoder<W>
+where
+    W: AsyncWriteExt + std::marker::Unpin + Send,
+{
+    fn encode(
+        &self,
+        command: &Command,
+        writer: &mut W,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// The client side counterpart of [`Responder`], reads the response the server sends for
+/// `request`.
+///
+/// Returns `None` without reading anything if the server does not answer `request`.
+/// The answer to `PROTOCOLS` is expected to list the same protocols this build knows about.
+pub trait ResponseDecoder<R>
+where
+    R: AsyncBufRead + std::marker::Unpin + Send,
+{
+    fn decode(
+        &self,
+        request: &Command,
+        reader: &mut R,
+    ) -> impl Future<Output = io::Result<Option<Response>>> + Send;
+}
+
+/// The `Enabled: {name}` and `Disabled: {name}` lines both protocols answer `PROTOCOLS` with,
+/// followed by an empty line that ends the list
+fn put_protocols(protocols: &[ProtocolStatus], dst: &mut BytesMut) {
+    for protocol in protocols {
+        match protocol {
+            ProtocolStatus::Enabled(proto) => {
+                dst.extend_from_slice(format!("Enabled: {proto}\n").as_bytes())
+            }
+            ProtocolStatus::Disabled(proto) => {
+                dst.extend_from_slice(format!("Disabled: {proto}\n").as_bytes())
+            }
+        }
+    }
+    dst.extend_from_slice(b"\n");
+}
+
+/// Read a single line of a response, a closed connection is reported as `UnexpectedEof`
+async fn read_response_line<R>(reader: &mut R) -> io::Result<String>
+where
+    R: AsyncBufRead + std::marker::Unpin + Send,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(line)
+}
+
+/// Read the `Enabled: {name}` and `Disabled: {name}` lines both protocols answer `PROTOCOLS` with
+/// up to the empty line that ends them. The server may know protocols this side doesn't, their
+/// names are owned.
+async fn read_protocols<R>(reader: &mut R) -> io::Result<Vec<ProtocolStatus>>
+where
+    R: AsyncBufRead + std::marker::Unpin + Send,
+{
+    let known = ParserTypes::get_status();
+    let mut protocols = Vec::with_capacity(known.len());
+    loop {
+        let line = read_response_line(reader).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(protocols);
+        }
+        let (status, name) = line
+            .split_once(": ")
+            .ok_or(Error::from(ErrorKind::InvalidData))?;
+        let name = known
+            .iter()
+            .map(|protocol| match protocol {
+                ProtocolStatus::Enabled(name) | ProtocolStatus::Disabled(name) => name,
+            })
+            .find(|known| *known == name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string().into());
+        protocols.push(match status {
+            "Enabled" => ProtocolStatus::Enabled(name),
+            "Disabled" => ProtocolStatus::Disabled(name),
+            _ => return Err(Error::from(ErrorKind::InvalidData)),
+        });
+    }
+}
@@ -1,10 +1,14 @@
 use std::io::{self, Error, ErrorKind};
 
+use bytes::{Buf, BufMut, BytesMut};
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{Canvas, Color, Command, Response};
 
-use super::{IOProtocol, Parser, Responder};
+use super::{
+    put_protocols, read_protocols, CommandEncoder, IOProtocol, Parser, Responder, ResponseDecoder,
+};
 
 const SIZE_BIN: u8 = 115;
 const PROTOCOLS_BIN: u8 = 116;
@@ -17,6 +21,17 @@ const SET_PX_W_BIN: u8 = 130;
 #[derive(Clone, Default)]
 pub struct BinaryParser {}
 
+fn help_text() -> String {
+    format!(
+"
+You found the binary protocol help text
+you can get this by sending ({HELP_BIN:02X}) to the server
+To get the size of a canvas, send ({SIZE_BIN:02X}) (u8 canvas) to the server
+To set a pixel using RGB, use ({SET_PX_RGB_BIN:02X}) (u8 canvas) (x as u16_le) (y as u16_le) (u8 r) (u8 g) (u8 b)
+",
+    )
+}
+
 impl<R: AsyncBufRead + AsyncBufReadExt + std::marker::Unpin> Parser<R> for BinaryParser {
     async fn parse(&self, reader: &mut R) -> io::Result<Command> {
         let fst = reader.read_u8().await;
@@ -88,6 +103,70 @@ impl<R: AsyncBufRead + AsyncBufReadExt + std::marker::Unpin> Parser<R> for Binar
     }
 }
 
+/// The length of a whole command, including the command byte itself
+fn command_length(command: u8) -> Option<usize> {
+    match command {
+        HELP_BIN | PROTOCOLS_BIN => Some(1),
+        SIZE_BIN => Some(2),
+        GET_PX_BIN => Some(6),
+        SET_PX_W_BIN => Some(7),
+        SET_PX_RGB_BIN => Some(9),
+        SET_PX_RGBA_BIN => Some(10),
+        _ => None,
+    }
+}
+
+impl Decoder for BinaryParser {
+    type Item = Command;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Command>> {
+        let Some(&command) = src.first() else {
+            return Ok(None);
+        };
+        let Some(length) = command_length(command) else {
+            tracing::error!("received illegal command: {command}");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        };
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+        let mut frame = src.split_to(length);
+        frame.advance(1);
+        Ok(Some(match command {
+            HELP_BIN => Command::Help,
+            PROTOCOLS_BIN => Command::Protocols,
+            SIZE_BIN => Command::Size(frame.get_u8()),
+            GET_PX_BIN => Command::GetPixel(frame.get_u8(), frame.get_u16(), frame.get_u16()),
+            SET_PX_W_BIN => Command::SetPixel(
+                frame.get_u8(),
+                frame.get_u16(),
+                frame.get_u16(),
+                Color::W8(frame.get_u8()),
+            ),
+            SET_PX_RGB_BIN => Command::SetPixel(
+                frame.get_u8(),
+                frame.get_u16(),
+                frame.get_u16(),
+                Color::RGB24(frame.get_u8(), frame.get_u8(), frame.get_u8()),
+            ),
+            SET_PX_RGBA_BIN => Command::SetPixel(
+                frame.get_u8(),
+                frame.get_u16(),
+                frame.get_u16(),
+                Color::RGBA32(
+                    frame.get_u8(),
+                    frame.get_u8(),
+                    frame.get_u8(),
+                    frame.get_u8(),
+                ),
+            ),
+            _ => unreachable!("command_length only knows valid commands"),
+        }))
+    }
+}
+
 impl IOProtocol for BinaryParser {
     fn change_canvas(&mut self, _canvas: Canvas) -> io::Result<()> {
         Err(Error::from(ErrorKind::Unsupported))
@@ -97,33 +176,11 @@ impl IOProtocol for BinaryParser {
 impl<W: AsyncWriteExt + std::marker::Unpin> Responder<W> for BinaryParser {
     async fn unparse(&self, response: Response, writer: &mut W) -> io::Result<()> {
         match response {
-            Response::Help => {
-                let help_text = format!(
-"
-You found the binary protocol help text
-you can get this by sending ({HELP_BIN:02X}) to the server
-To get the size of a canvas, send ({SIZE_BIN:02X}) (u8 canvas) to the server
-To set a pixel using RGB, use ({SET_PX_RGB_BIN:02X}) (u8 canvas) (x as u16_le) (y as u16_le) (u8 r) (u8 g) (u8 b)
-",
-);
-                writer.write_all(help_text.as_bytes()).await
-            }
+            Response::Help => writer.write_all(help_text().as_bytes()).await,
             Response::Protocols(protos) => {
-                for protocol in protos {
-                    match protocol {
-                        crate::ProtocolStatus::Enabled(proto) => {
-                            writer
-                                .write_all(format!("Enabled: {proto}\n").as_bytes())
-                                .await?;
-                        }
-                        crate::ProtocolStatus::Disabled(proto) => {
-                            writer
-                                .write_all(format!("Disabled: {proto}\n").as_bytes())
-                                .await?;
-                        }
-                    }
-                }
-                Ok(())
+                let mut lines = BytesMut::new();
+                put_protocols(&protos, &mut lines);
+                writer.write_all(&lines).await
             }
             Response::Size(x, y) => {
                 writer.write_u16(x).await?;
@@ -138,11 +195,99 @@ To set a pixel using RGB, use ({SET_PX_RGB_BIN:02X}) (u8 canvas) (x as u16_le) (
     }
 }
 
+impl Encoder<Response> for BinaryParser {
+    type Error = io::Error;
+
+    fn encode(&mut self, response: Response, dst: &mut BytesMut) -> io::Result<()> {
+        match response {
+            Response::Help => dst.extend_from_slice(help_text().as_bytes()),
+            Response::Protocols(protos) => put_protocols(&protos, dst),
+            Response::Size(x, y) => {
+                dst.put_u16(x);
+                dst.put_u16(y);
+            }
+            Response::GetPixel(_, _, c) => dst.extend_from_slice(&c),
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWriteExt + std::marker::Unpin + Send> CommandEncoder<W> for BinaryParser {
+    /// The binary protocol has no way to change canvas or protocol, those commands are rejected
+    /// with `Unsupported`
+    async fn encode(&self, command: &Command, writer: &mut W) -> io::Result<()> {
+        match command {
+            Command::Help => writer.write_u8(HELP_BIN).await,
+            Command::Protocols => writer.write_u8(PROTOCOLS_BIN).await,
+            Command::Size(canvas) => writer.write_all(&[SIZE_BIN, *canvas]).await,
+            Command::GetPixel(canvas, x, y) => {
+                writer.write_all(&[GET_PX_BIN, *canvas]).await?;
+                writer.write_u16(*x).await?;
+                writer.write_u16(*y).await
+            }
+            Command::SetPixel(canvas, x, y, color) => {
+                let command = match color {
+                    Color::RGB24(..) => SET_PX_RGB_BIN,
+                    Color::RGBA32(..) => SET_PX_RGBA_BIN,
+                    Color::W8(_) => SET_PX_W_BIN,
+                };
+                writer.write_all(&[command, *canvas]).await?;
+                writer.write_u16(*x).await?;
+                writer.write_u16(*y).await?;
+                match color {
+                    Color::RGB24(red, green, blue) => {
+                        writer.write_all(&[*red, *green, *blue]).await
+                    }
+                    Color::RGBA32(red, green, blue, alpha) => {
+                        writer.write_all(&[*red, *green, *blue, *alpha]).await
+                    }
+                    Color::W8(white) => writer.write_u8(*white).await,
+                }
+            }
+            Command::ChangeCanvas(_) | Command::ChangeProtocol(_) | Command::Name(_) => {
+                Err(Error::from(ErrorKind::Unsupported))
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + std::marker::Unpin + Send> ResponseDecoder<R> for BinaryParser {
+    async fn decode(&self, request: &Command, reader: &mut R) -> io::Result<Option<Response>> {
+        match request {
+            Command::Help => {
+                let mut help = vec![0; help_text().len()];
+                reader.read_exact(&mut help).await?;
+                Ok(Some(Response::Help))
+            }
+            Command::Protocols => Ok(Some(Response::Protocols(read_protocols(reader).await?))),
+            Command::Size(_) => {
+                let horizontal = reader.read_u16().await?;
+                let vertical = reader.read_u16().await?;
+                Ok(Some(Response::Size(horizontal, vertical)))
+            }
+            Command::GetPixel(_, x, y) => {
+                let mut color = [0; 3];
+                reader.read_exact(&mut color).await?;
+                Ok(Some(Response::GetPixel(*x, *y, color)))
+            }
+            Command::SetPixel(..)
+            | Command::ChangeCanvas(_)
+            | Command::ChangeProtocol(_)
+            | Command::Name(_) => Ok(None),
+        }
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::needless_return)]
 mod tests {
     use super::*;
+    use crate::{flutclient::ParserTypes, Protocol, ProtocolStatus};
+    use futures::StreamExt;
+    use rand::Rng;
+    use test_case::test_case;
     use tokio::io::BufReader;
+    use tokio_util::codec::FramedRead;
 
     #[tokio::test]
     async fn test_bin_help_parse() {
@@ -276,4 +421,157 @@ mod tests {
             Command::SetPixel(1, 0x6942, 0x4270, Color::RGBA32(0x82, 0x00, 0xff, 0xa0))
         );
     }
+
+    #[test_case(Command::Help ; "help")]
+    #[test_case(Command::Protocols ; "protocols")]
+    #[test_case(Command::Size(3) ; "size")]
+    #[test_case(Command::GetPixel(3, 0x6942, 0x4269) ; "get pixel")]
+    #[test_case(Command::SetPixel(1, 0x6942, 0x4269, Color::W8(0x82)) ; "set w")]
+    #[test_case(Command::SetPixel(1, 0x4269, 0x6942, Color::RGB24(0x82, 0x00, 0xff)) ; "set rgb")]
+    #[test_case(Command::SetPixel(1, 0, 0xffff, Color::RGBA32(0x82, 0x00, 0xff, 0xa0)) ; "set rgba")]
+    #[tokio::test]
+    async fn test_bin_encode_roundtrip(command: Command) {
+        let parser = BinaryParser::default();
+        let mut buf = Vec::new();
+        parser.encode(&command, &mut buf).await.unwrap();
+        let mut bufreader = BufReader::new(buf.as_slice());
+        assert_eq!(parser.parse(&mut bufreader).await.unwrap(), command);
+    }
+
+    #[tokio::test]
+    async fn test_bin_encode_roundtrip_random() {
+        let parser = BinaryParser::default();
+        let mut rng = rand::rng();
+        for _ in 0..1000 {
+            let command = Command::SetPixel(rng.random(), rng.random(), rng.random(), rng.random());
+            let mut buf = Vec::new();
+            parser.encode(&command, &mut buf).await.unwrap();
+            let mut bufreader = BufReader::new(buf.as_slice());
+            assert_eq!(parser.parse(&mut bufreader).await.unwrap(), command);
+        }
+    }
+
+    #[test_case(Command::ChangeCanvas(1) ; "change canvas")]
+    #[test_case(Command::ChangeProtocol(Protocol::Text) ; "change protocol")]
+    #[tokio::test]
+    async fn test_bin_encode_unsupported(command: Command) {
+        let parser = BinaryParser::default();
+        let mut buf = Vec::new();
+        let err = parser.encode(&command, &mut buf).await;
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test_case(Command::Help, Response::Help ; "help")]
+    #[test_case(Command::Size(0), Response::Size(800, 600) ; "size")]
+    #[test_case(Command::GetPixel(0, 12, 34), Response::GetPixel(12, 34, [0x88, 0x00, 0xff]) ; "get pixel")]
+    #[tokio::test]
+    async fn test_bin_decode_roundtrip(request: Command, response: Response) {
+        let parser = BinaryParser::default();
+        let mut buf = Vec::new();
+        parser.unparse(response.clone(), &mut buf).await.unwrap();
+        let mut bufreader = BufReader::new(buf.as_slice());
+        assert_eq!(
+            parser.decode(&request, &mut bufreader).await.unwrap(),
+            Some(response)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bin_decode_protocols() {
+        crate::protocols::registry::tests::register_csv();
+        let parser = BinaryParser::default();
+        let response = Response::Protocols(ParserTypes::get_status());
+        let mut buf = Vec::new();
+        parser.unparse(response.clone(), &mut buf).await.unwrap();
+        let mut bufreader = BufReader::new(buf.as_slice());
+        assert_eq!(
+            parser
+                .decode(&Command::Protocols, &mut bufreader)
+                .await
+                .unwrap(),
+            Some(response)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bin_codec_partial_frame() {
+        let mut parser = BinaryParser::default();
+        let mut buf = BytesMut::new();
+        for &byte in &[
+            SET_PX_RGBA_BIN,
+            0x01,
+            0x42,
+            0x69,
+            0x69,
+            0x42,
+            0x82,
+            0x00,
+            0xff,
+        ] {
+            buf.extend_from_slice(&[byte]);
+            assert_eq!(Decoder::decode(&mut parser, &mut buf).unwrap(), None);
+        }
+        buf.extend_from_slice(&[0xa0, SIZE_BIN]);
+        assert_eq!(
+            Decoder::decode(&mut parser, &mut buf).unwrap(),
+            Some(Command::SetPixel(
+                1,
+                0x4269,
+                0x6942,
+                Color::RGBA32(0x82, 0x00, 0xff, 0xa0)
+            ))
+        );
+        assert_eq!(Decoder::decode(&mut parser, &mut buf).unwrap(), None);
+        assert_eq!(&buf[..], &[SIZE_BIN]);
+        let err = parser.decode_eof(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+    }
+
+    #[tokio::test]
+    async fn test_bin_codec_illegal_command() {
+        let mut parser = BinaryParser::default();
+        let mut buf = BytesMut::from(&[0xff, 0x00][..]);
+        let err = Decoder::decode(&mut parser, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_bin_codec_matches_parser() {
+        let mut rng = rand::rng();
+        let parser = BinaryParser::default();
+        let mut buf = Vec::new();
+        let mut expected = Vec::new();
+        for _ in 0..1000 {
+            let command = Command::SetPixel(rng.random(), rng.random(), rng.random(), rng.random());
+            parser.encode(&command, &mut buf).await.unwrap();
+            expected.push(command);
+        }
+        // split the stream at arbitrary points so frames cross read boundaries
+        let mut builder = tokio_test::io::Builder::new();
+        for chunk in buf.chunks(7) {
+            builder.read(chunk);
+        }
+        let commands: Vec<Command> = FramedRead::new(builder.build(), parser)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(commands, expected);
+    }
+
+    #[test_case(Response::Help ; "help")]
+    #[test_case(Response::Protocols(vec![ProtocolStatus::Enabled("binary".into())]) ; "protocols")]
+    #[test_case(Response::Size(800, 600) ; "size")]
+    #[test_case(Response::GetPixel(12, 34, [0x88, 0x00, 0xff]) ; "get pixel")]
+    #[tokio::test]
+    async fn test_bin_codec_encode_matches_unparse(response: Response) {
+        let mut parser = BinaryParser::default();
+        let mut expected = Vec::new();
+        parser
+            .unparse(response.clone(), &mut expected)
+            .await
+            .unwrap();
+        let mut buf = BytesMut::new();
+        Encoder::encode(&mut parser, response, &mut buf).unwrap();
+        assert_eq!(&buf[..], &expected[..]);
+    }
 }
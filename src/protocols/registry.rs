@@ -0,0 +1,228 @@
+use std::{
+    io::{self, Error, ErrorKind},
+    sync::RwLock,
+};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{flutclient::ParserTypes, Canvas, Command, ProtocolStatus, Response};
+
+use super::{IOProtocol, Parser, Responder};
+
+/// A protocol implemented outside of flurry.
+///
+/// Every connection that switches to the protocol gets its own instance from the factory it was
+/// registered with, so per connection state (like the current canvas) can live in `self`.
+#[async_trait]
+pub trait CustomProtocol: CustomProtocolClone + Send + Sync {
+    async fn parse(&self, reader: &mut (dyn AsyncBufRead + Unpin + Send)) -> io::Result<Command>;
+    async fn unparse(
+        &self,
+        response: Response,
+        writer: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> io::Result<()>;
+    fn change_canvas(&mut self, canvas: Canvas) -> io::Result<()>;
+}
+
+/// Lets connections clone their parser, implemented for every `CustomProtocol` that is `Clone`
+pub trait CustomProtocolClone {
+    fn clone_box(&self) -> Box<dyn CustomProtocol>;
+}
+
+impl<T: CustomProtocol + Clone + 'static> CustomProtocolClone for T {
+    fn clone_box(&self) -> Box<dyn CustomProtocol> {
+        Box::new(self.clone())
+    }
+}
+
+type ProtocolFactory = Box<dyn Fn() -> Box<dyn CustomProtocol> + Send + Sync>;
+
+static CUSTOM_PROTOCOLS: RwLock<Vec<(&'static str, ProtocolFactory)>> = RwLock::new(Vec::new());
+
+/// Make a protocol available as `PROTOCOL {name}`, this should be done at startup, before any
+/// clients connect.
+///
+/// # Errors
+///
+/// This function will return `AlreadyExists` if a protocol with the same name is already known
+pub fn register_protocol<F>(name: &'static str, factory: F) -> io::Result<()>
+where
+    F: Fn() -> Box<dyn CustomProtocol> + Send + Sync + 'static,
+{
+    let builtin = ParserTypes::builtin_status()
+        .into_iter()
+        .any(|protocol| match protocol {
+            ProtocolStatus::Enabled(known) | ProtocolStatus::Disabled(known) => known == name,
+        });
+    let mut protocols = CUSTOM_PROTOCOLS.write().expect("RWlock didn't exit nicely");
+    if builtin || protocols.iter().any(|(known, _)| *known == name) {
+        return Err(Error::from(ErrorKind::AlreadyExists));
+    }
+    tracing::info!("Registered {name}");
+    protocols.push((name, Box::new(factory)));
+    Ok(())
+}
+
+/// The names of all registered protocols, in the order they were registered
+pub(crate) fn custom_protocol_names() -> Vec<&'static str> {
+    CUSTOM_PROTOCOLS
+        .read()
+        .expect("RWlock didn't exit nicely")
+        .iter()
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Look up a registered protocol, returning the `'static` name it was registered with
+pub(crate) fn find_custom_protocol(name: &str) -> Option<&'static str> {
+    CUSTOM_PROTOCOLS
+        .read()
+        .expect("RWlock didn't exit nicely")
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(known, _)| *known)
+}
+
+/// The connection side of a registered protocol, dispatches dynamically to the implementation
+pub struct CustomParser(Box<dyn CustomProtocol>);
+
+impl CustomParser {
+    pub(crate) fn new(name: &str) -> Option<CustomParser> {
+        CUSTOM_PROTOCOLS
+            .read()
+            .expect("RWlock didn't exit nicely")
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, factory)| CustomParser(factory()))
+    }
+}
+
+impl Clone for CustomParser {
+    fn clone(&self) -> Self {
+        CustomParser(self.0.clone_box())
+    }
+}
+
+impl<R: AsyncBufRead + std::marker::Unpin + Send> Parser<R> for CustomParser {
+    async fn parse(&self, reader: &mut R) -> io::Result<Command> {
+        self.0.parse(reader).await
+    }
+}
+
+impl IOProtocol for CustomParser {
+    fn change_canvas(&mut self, canvas: Canvas) -> io::Result<()> {
+        self.0.change_canvas(canvas)
+    }
+}
+
+impl<W: AsyncWriteExt + std::marker::Unpin + Send> Responder<W> for CustomParser {
+    async fn unparse(&self, response: Response, writer: &mut W) -> io::Result<()> {
+        self.0.unparse(response, writer).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Once};
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        flutclient::FlutClient,
+        grid::{Flut, Grid},
+        Color,
+    };
+
+    /// Sets pixels with `x,y,rrggbb` lines and answers `size` with `x,y`
+    #[derive(Clone, Default)]
+    struct Csv {
+        canvas: Canvas,
+    }
+
+    #[async_trait]
+    impl CustomProtocol for Csv {
+        async fn parse(
+            &self,
+            reader: &mut (dyn AsyncBufRead + Unpin + Send),
+        ) -> io::Result<Command> {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+            let invalid = || Error::from(ErrorKind::InvalidInput);
+            match line.trim().split(',').collect::<Vec<_>>()[..] {
+                ["size"] => Ok(Command::Size(self.canvas)),
+                [x, y, color] => {
+                    let color = u32::from_str_radix(color, 16).map_err(|_| invalid())?;
+                    let [_, r, g, b] = color.to_be_bytes();
+                    Ok(Command::SetPixel(
+                        self.canvas,
+                        x.parse().map_err(|_| invalid())?,
+                        y.parse().map_err(|_| invalid())?,
+                        Color::RGB24(r, g, b),
+                    ))
+                }
+                _ => Err(invalid()),
+            }
+        }
+
+        async fn unparse(
+            &self,
+            response: Response,
+            writer: &mut (dyn AsyncWrite + Unpin + Send),
+        ) -> io::Result<()> {
+            match response {
+                Response::Size(x, y) => writer.write_all(format!("{x},{y}\n").as_bytes()).await,
+                _ => Err(Error::from(ErrorKind::Unsupported)),
+            }
+        }
+
+        fn change_canvas(&mut self, canvas: Canvas) -> io::Result<()> {
+            self.canvas = canvas;
+            Ok(())
+        }
+    }
+
+    /// Tests share one registry, so every test that depends on it registers through here to keep
+    /// the set of protocols the same no matter which tests run first
+    pub(crate) fn register_csv() {
+        static REGISTER: Once = Once::new();
+        REGISTER.call_once(|| register_protocol("csv", || Box::new(Csv::default())).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_register_duplicate() {
+        register_csv();
+        let err = register_protocol("csv", || Box::new(Csv::default()));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        let err = register_protocol("text", || Box::new(Csv::default()));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        let err = register_protocol("binary", || Box::new(Csv::default()));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn test_registered_status() {
+        register_csv();
+        let status = ParserTypes::get_status();
+        assert_eq!(status[..2], ParserTypes::builtin_status());
+        assert!(status.contains(&ProtocolStatus::Enabled("csv".into())));
+    }
+
+    #[tokio::test]
+    async fn test_switch_to_registered() {
+        register_csv();
+        let grids: Arc<[Flut<u32>]> = [Flut::init(800, 600, 0)].into();
+        let reader = tokio_test::io::Builder::new()
+            .read(b"PROTOCOL csv\n")
+            .read(b"3,4,123456\nsize\n")
+            .build();
+        let writer = tokio_test::io::Builder::new().write(b"800,600\n").build();
+        let mut client = FlutClient::new(reader, writer, grids.clone());
+        let _ = client.process_socket().await;
+        assert_eq!(grids[0].get(3, 4), Some(0x12_34_56_ff));
+    }
+}
@@ -1,20 +1,28 @@
 use atoi_radix10::parse_from_str;
+use bytes::BytesMut;
 use std::io::{self, Error, ErrorKind};
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{
-    config::{GRID_LENGTH, HELP_TEXT},
+    config::{HELP_TEXT, MAX_CLIENT_NAME},
     Canvas, Color, Command, Coordinate, Protocol, Response,
 };
 
-use super::{IOProtocol, Parser, Responder};
+use super::{
+    find_custom_protocol, put_protocols, read_protocols, read_response_line, CommandEncoder,
+    IOProtocol, Parser, Responder, ResponseDecoder,
+};
+
+/// The longest line the codec buffers, newline included. Commands are far shorter, so a longer
+/// line is never going to parse.
+const MAX_LINE_LENGTH: usize = 256;
 
 #[derive(Clone, Default)]
 pub struct TextParser {
     canvas: Canvas,
 }
 
-#[allow(dead_code)]
 fn parse_coordinate(string: &str) -> io::Result<Coordinate> {
     match parse_from_str(string) {
         Ok(coord) => Ok(coord),
@@ -64,11 +72,39 @@ fn parse_color(color: &str) -> io::Result<Color> {
     }
 }
 
+fn format_color(color: &Color) -> String {
+    match color {
+        Color::RGB24(r, g, b) => format!("{r:02X}{g:02X}{b:02X}"),
+        Color::RGBA32(r, g, b, a) => format!("{r:02X}{g:02X}{b:02X}{a:02X}"),
+        Color::W8(w) => format!("{w:02X}"),
+    }
+}
+
 impl TextParser {
     pub fn new(canvas: Canvas) -> TextParser {
         TextParser { canvas }
     }
 
+    fn parse_line(&self, line: &str) -> io::Result<Command> {
+        if line.starts_with("HELP") {
+            Ok(Command::Help)
+        } else if line.starts_with("PROTOCOLS") {
+            Ok(Command::Protocols)
+        } else if line.starts_with("SIZE") {
+            Ok(Command::Size(self.canvas))
+        } else if line.starts_with("PX ") {
+            self.parse_pixel(line)
+        } else if line.starts_with("CANVAS ") {
+            TextParser::parse_canvas(line)
+        } else if line.starts_with("PROTOCOL ") {
+            TextParser::parse_protocol(line)
+        } else if line.starts_with("NAME ") {
+            TextParser::parse_name(line)
+        } else {
+            Err(Error::from(ErrorKind::InvalidInput))
+        }
+    }
+
     fn parse_pixel(&self, line: &str) -> io::Result<Command> {
         let mut split = line.trim().split(' ');
 
@@ -106,8 +142,18 @@ impl TextParser {
         match protocol {
             "binary" => Ok(Command::ChangeProtocol(Protocol::Binary)),
             "text" => Ok(Command::ChangeProtocol(Protocol::Text)),
-            _ => Err(Error::from(ErrorKind::InvalidInput)),
+            _ => match find_custom_protocol(protocol) {
+                Some(name) => Ok(Command::ChangeProtocol(Protocol::Custom(name))),
+                None => Err(Error::from(ErrorKind::InvalidInput)),
+            },
+        }
+    }
+    fn parse_name(line: &str) -> io::Result<Command> {
+        let name = line["NAME ".len()..].trim();
+        if name.is_empty() || name.len() > MAX_CLIENT_NAME {
+            return Err(Error::from(ErrorKind::InvalidInput));
         }
+        Ok(Command::Name(name.to_string()))
     }
 }
 
@@ -115,32 +161,52 @@ impl<R: AsyncBufRead + AsyncBufReadExt + std::marker::Unpin> Parser<R> for TextP
     async fn parse(&self, reader: &mut R) -> io::Result<Command> {
         let mut line = String::new();
         if reader.read_line(&mut line).await.is_ok() {
-            if line.starts_with("HELP") {
-                return Ok(Command::Help);
-            } else if line.starts_with("PROTOCOLS") {
-                return Ok(Command::Protocols);
-            } else if line.starts_with("SIZE") {
-                return Ok(Command::Size(self.canvas));
-            } else if line.starts_with("PX ") {
-                return self.parse_pixel(&line);
-            } else if line.starts_with("CANVAS ") {
-                return TextParser::parse_canvas(&line);
-            } else if line.starts_with("PROTOCOL ") {
-                return TextParser::parse_protocol(&line);
-            }
+            return self.parse_line(&line);
         }
         Err(Error::from(ErrorKind::InvalidInput))
     }
 }
 
+impl Decoder for TextParser {
+    type Item = Command;
+    type Error = io::Error;
+
+    /// # Errors
+    ///
+    /// Besides invalid commands this returns `InvalidData` once [`MAX_LINE_LENGTH`] bytes came
+    /// without a newline, so a client can't make the buffer grow without bound
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Command>> {
+        let searched = &src[..src.len().min(MAX_LINE_LENGTH)];
+        let Some(end) = searched.iter().position(|&c| c == b'\n') else {
+            if src.len() >= MAX_LINE_LENGTH {
+                return Err(Error::from(ErrorKind::InvalidData));
+            }
+            return Ok(None);
+        };
+        let line = src.split_to(end + 1);
+        let line = std::str::from_utf8(&line).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+        self.parse_line(line).map(Some)
+    }
+
+    /// The last command of a stream doesn't need a newline, just like with [`Parser`]
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Command>> {
+        match self.decode(src)? {
+            Some(command) => Ok(Some(command)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let line = src.split();
+                let line =
+                    std::str::from_utf8(&line).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+                self.parse_line(line).map(Some)
+            }
+        }
+    }
+}
+
 impl IOProtocol for TextParser {
     fn change_canvas(&mut self, canvas: Canvas) -> io::Result<()> {
-        if (canvas as usize) < GRID_LENGTH {
-            self.canvas = canvas;
-            Ok(())
-        } else {
-            Err(Error::from(ErrorKind::InvalidInput))
-        }
+        self.canvas = canvas;
+        Ok(())
     }
 }
 
@@ -149,21 +215,9 @@ impl<W: AsyncWriteExt + std::marker::Unpin> Responder<W> for TextParser {
         match response {
             Response::Help => writer.write_all(HELP_TEXT).await,
             Response::Protocols(protos) => {
-                for protocol in protos {
-                    match protocol {
-                        crate::ProtocolStatus::Enabled(proto) => {
-                            writer
-                                .write_all(format!("Enabled: {proto}\n").as_bytes())
-                                .await?;
-                        }
-                        crate::ProtocolStatus::Disabled(proto) => {
-                            writer
-                                .write_all(format!("Disabled: {proto}\n").as_bytes())
-                                .await?;
-                        }
-                    }
-                }
-                Ok(())
+                let mut lines = BytesMut::new();
+                put_protocols(&protos, &mut lines);
+                writer.write_all(&lines).await
             }
             Response::Size(x, y) => writer.write_all(format!("SIZE {x} {y}\n").as_bytes()).await,
             Response::GetPixel(x, y, color) => {
@@ -181,11 +235,117 @@ impl<W: AsyncWriteExt + std::marker::Unpin> Responder<W> for TextParser {
     }
 }
 
+impl Encoder<Response> for TextParser {
+    type Error = io::Error;
+
+    fn encode(&mut self, response: Response, dst: &mut BytesMut) -> io::Result<()> {
+        match response {
+            Response::Help => dst.extend_from_slice(HELP_TEXT),
+            Response::Protocols(protos) => put_protocols(&protos, dst),
+            Response::Size(x, y) => dst.extend_from_slice(format!("SIZE {x} {y}\n").as_bytes()),
+            Response::GetPixel(x, y, color) => dst.extend_from_slice(
+                format!(
+                    "PX {x} {y} {:02X}{:02X}{:02X}\n",
+                    color[0], color[1], color[2]
+                )
+                .as_bytes(),
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWriteExt + std::marker::Unpin + Send> CommandEncoder<W> for TextParser {
+    /// Commands for a different canvas than the current one can't be expressed without
+    /// switching canvas first, so they are rejected with `InvalidInput`
+    async fn encode(&self, command: &Command, writer: &mut W) -> io::Result<()> {
+        match command {
+            Command::Help => writer.write_all(b"HELP\n").await,
+            Command::Protocols => writer.write_all(b"PROTOCOLS\n").await,
+            Command::Size(canvas) if *canvas == self.canvas => writer.write_all(b"SIZE\n").await,
+            Command::GetPixel(canvas, x, y) if *canvas == self.canvas => {
+                writer.write_all(format!("PX {x} {y}\n").as_bytes()).await
+            }
+            Command::SetPixel(canvas, x, y, color) if *canvas == self.canvas => {
+                writer
+                    .write_all(format!("PX {x} {y} {}\n", format_color(color)).as_bytes())
+                    .await
+            }
+            Command::ChangeCanvas(canvas) => {
+                writer
+                    .write_all(format!("CANVAS {canvas}\n").as_bytes())
+                    .await
+            }
+            Command::ChangeProtocol(Protocol::Text) => writer.write_all(b"PROTOCOL text\n").await,
+            Command::ChangeProtocol(Protocol::Binary) => {
+                writer.write_all(b"PROTOCOL binary\n").await
+            }
+            Command::ChangeProtocol(Protocol::Custom(name)) => {
+                writer
+                    .write_all(format!("PROTOCOL {name}\n").as_bytes())
+                    .await
+            }
+            Command::Name(name) => writer.write_all(format!("NAME {name}\n").as_bytes()).await,
+            Command::Size(_) | Command::GetPixel(..) | Command::SetPixel(..) => {
+                Err(Error::from(ErrorKind::InvalidInput))
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + std::marker::Unpin + Send> ResponseDecoder<R> for TextParser {
+    async fn decode(&self, request: &Command, reader: &mut R) -> io::Result<Option<Response>> {
+        match request {
+            Command::Help => {
+                let mut help = vec![0; HELP_TEXT.len()];
+                reader.read_exact(&mut help).await?;
+                Ok(Some(Response::Help))
+            }
+            Command::Protocols => Ok(Some(Response::Protocols(read_protocols(reader).await?))),
+            Command::Size(_) => {
+                let line = read_response_line(reader).await?;
+                let mut split = line.trim().split(' ');
+                match (split.next(), split.next(), split.next()) {
+                    (Some("SIZE"), Some(x), Some(y)) => Ok(Some(Response::Size(
+                        parse_coordinate(x)?,
+                        parse_coordinate(y)?,
+                    ))),
+                    _ => Err(Error::from(ErrorKind::InvalidData)),
+                }
+            }
+            Command::GetPixel(..) => {
+                let line = read_response_line(reader).await?;
+                let mut split = line.trim().split(' ');
+                match (split.next(), split.next(), split.next(), split.next()) {
+                    (Some("PX"), Some(x), Some(y), Some(color)) => match parse_color(color)? {
+                        Color::RGB24(r, g, b) => Ok(Some(Response::GetPixel(
+                            parse_coordinate(x)?,
+                            parse_coordinate(y)?,
+                            [r, g, b],
+                        ))),
+                        _ => Err(Error::from(ErrorKind::InvalidData)),
+                    },
+                    _ => Err(Error::from(ErrorKind::InvalidData)),
+                }
+            }
+            Command::SetPixel(..)
+            | Command::ChangeCanvas(_)
+            | Command::ChangeProtocol(_)
+            | Command::Name(_) => Ok(None),
+        }
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::needless_return)]
 mod tests {
     use super::*;
+    use crate::{flutclient::ParserTypes, ProtocolStatus};
+    use futures::StreamExt;
+    use rand::Rng;
+    use test_case::test_case;
     use tokio::io::BufReader;
+    use tokio_util::codec::FramedRead;
 
     #[tokio::test]
     async fn test_help_parse() {
@@ -214,6 +374,22 @@ mod tests {
         assert_eq!(thingy.unwrap(), Command::ChangeCanvas(12));
     }
 
+    #[tokio::test]
+    async fn test_name_parse() {
+        let parser = TextParser::default();
+        let too_long = format!("NAME {}\n", "a".repeat(MAX_CLIENT_NAME + 1));
+        let reader = tokio_test::io::Builder::new()
+            .read(b"NAME team red \n")
+            .read(b"NAME \n")
+            .read(too_long.as_bytes())
+            .build();
+        let mut bufreader = BufReader::new(reader);
+        let name = parser.parse(&mut bufreader).await;
+        assert_eq!(name.unwrap(), Command::Name("team red".to_string()));
+        assert!(parser.parse(&mut bufreader).await.is_err());
+        assert!(parser.parse(&mut bufreader).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_px_set_w_parse() {
         let parser = TextParser::default();
@@ -322,4 +498,207 @@ mod tests {
         assert_eq!(thingy.unwrap(), Command::ChangeCanvas(12));
         assert_eq!(thingy2.unwrap(), Command::Size(0));
     }
+
+    #[test_case(Command::Help ; "help")]
+    #[test_case(Command::Protocols ; "protocols")]
+    #[test_case(Command::Size(3) ; "size")]
+    #[test_case(Command::GetPixel(3, 28283, 29991) ; "get pixel")]
+    #[test_case(Command::SetPixel(3, 28283, 29991, Color::W8(0x81)) ; "set w")]
+    #[test_case(Command::SetPixel(3, 28283, 29991, Color::RGB24(0x88, 0x00, 0xff)) ; "set rgb")]
+    #[test_case(Command::SetPixel(3, 0, 0, Color::RGBA32(0xab, 0x0c, 0x3f, 0x88)) ; "set rgba")]
+    #[test_case(Command::ChangeCanvas(12) ; "change canvas")]
+    #[test_case(Command::ChangeProtocol(Protocol::Text) ; "change protocol text")]
+    #[test_case(Command::ChangeProtocol(Protocol::Binary) ; "change protocol binary")]
+    #[test_case(Command::Name("team red".to_string()) ; "name")]
+    #[tokio::test]
+    async fn test_encode_roundtrip(command: Command) {
+        let parser = TextParser::new(3);
+        let mut buf = Vec::new();
+        parser.encode(&command, &mut buf).await.unwrap();
+        let mut bufreader = BufReader::new(buf.as_slice());
+        assert_eq!(parser.parse(&mut bufreader).await.unwrap(), command);
+    }
+
+    #[tokio::test]
+    async fn test_encode_roundtrip_random() {
+        let parser = TextParser::default();
+        let mut rng = rand::rng();
+        for _ in 0..1000 {
+            let command = Command::SetPixel(0, rng.random(), rng.random(), rng.random());
+            let mut buf = Vec::new();
+            parser.encode(&command, &mut buf).await.unwrap();
+            let mut bufreader = BufReader::new(buf.as_slice());
+            assert_eq!(parser.parse(&mut bufreader).await.unwrap(), command);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encode_other_canvas() {
+        let parser = TextParser::default();
+        let mut buf = Vec::new();
+        let err = parser.encode(&Command::Size(1), &mut buf).await;
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
+    #[test_case(Command::Help, Response::Help ; "help")]
+    #[test_case(Command::Size(0), Response::Size(800, 600) ; "size")]
+    #[test_case(Command::GetPixel(0, 12, 34), Response::GetPixel(12, 34, [0x88, 0x00, 0xff]) ; "get pixel")]
+    #[tokio::test]
+    async fn test_decode_roundtrip(request: Command, response: Response) {
+        let parser = TextParser::default();
+        let mut buf = Vec::new();
+        parser.unparse(response.clone(), &mut buf).await.unwrap();
+        let mut bufreader = BufReader::new(buf.as_slice());
+        assert_eq!(
+            parser.decode(&request, &mut bufreader).await.unwrap(),
+            Some(response)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_protocols() {
+        crate::protocols::registry::tests::register_csv();
+        let parser = TextParser::default();
+        let response = Response::Protocols(ParserTypes::get_status());
+        let mut buf = Vec::new();
+        parser.unparse(response.clone(), &mut buf).await.unwrap();
+        let mut bufreader = BufReader::new(buf.as_slice());
+        assert_eq!(
+            parser
+                .decode(&Command::Protocols, &mut bufreader)
+                .await
+                .unwrap(),
+            Some(response)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_unknown_protocols() {
+        let parser = TextParser::default();
+        let reply = b"Enabled: text\nDisabled: binary\nEnabled: remote\n\nSIZE 800 600\n";
+        let mut bufreader = BufReader::new(&reply[..]);
+        assert_eq!(
+            parser
+                .decode(&Command::Protocols, &mut bufreader)
+                .await
+                .unwrap(),
+            Some(Response::Protocols(vec![
+                ProtocolStatus::Enabled("text".into()),
+                ProtocolStatus::Disabled("binary".into()),
+                ProtocolStatus::Enabled("remote".to_string().into()),
+            ]))
+        );
+        // nothing of the list is left for the next response
+        assert_eq!(
+            parser
+                .decode(&Command::Size(0), &mut bufreader)
+                .await
+                .unwrap(),
+            Some(Response::Size(800, 600))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_no_response() {
+        let parser = TextParser::default();
+        let mut bufreader = BufReader::new(&b"SIZE 800 600\n"[..]);
+        let command = Command::SetPixel(0, 1, 2, Color::W8(0));
+        assert_eq!(parser.decode(&command, &mut bufreader).await.unwrap(), None);
+        assert_eq!(
+            parser
+                .decode(&Command::Size(0), &mut bufreader)
+                .await
+                .unwrap(),
+            Some(Response::Size(800, 600))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_codec_partial_frame() {
+        let mut parser = TextParser::default();
+        let mut buf = BytesMut::new();
+        for &byte in b"PX 28283 29991 8800f" {
+            buf.extend_from_slice(&[byte]);
+            assert_eq!(Decoder::decode(&mut parser, &mut buf).unwrap(), None);
+        }
+        buf.extend_from_slice(b"f\nSIZE");
+        assert_eq!(
+            Decoder::decode(&mut parser, &mut buf).unwrap(),
+            Some(Command::SetPixel(
+                0,
+                28283,
+                29991,
+                Color::RGB24(0x88, 0x00, 0xff)
+            ))
+        );
+        assert_eq!(Decoder::decode(&mut parser, &mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"SIZE");
+        assert_eq!(parser.decode_eof(&mut buf).unwrap(), Some(Command::Size(0)));
+        assert_eq!(parser.decode_eof(&mut buf).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_codec_invalid_utf8() {
+        let mut parser = TextParser::default();
+        let mut buf = BytesMut::from(&b"PX \xff 1\n"[..]);
+        let err = Decoder::decode(&mut parser, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_codec_line_too_long() {
+        let mut parser = TextParser::default();
+        let mut buf = BytesMut::from(&[b'P'; MAX_LINE_LENGTH - 1][..]);
+        assert_eq!(Decoder::decode(&mut parser, &mut buf).unwrap(), None);
+        buf.extend_from_slice(b"X");
+        let err = Decoder::decode(&mut parser, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut buf = BytesMut::from(&b"SIZE\n"[..]);
+        buf.extend_from_slice(&[b'P'; MAX_LINE_LENGTH]);
+        assert_eq!(
+            Decoder::decode(&mut parser, &mut buf).unwrap(),
+            Some(Command::Size(0))
+        );
+        assert!(Decoder::decode(&mut parser, &mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_codec_framed_read() {
+        let reader = tokio_test::io::Builder::new()
+            .read(b"CANVAS 1")
+            .read(b"2\nSI")
+            .read(b"ZE\nPX 1 2\n")
+            .build();
+        let commands: Vec<Command> = FramedRead::new(reader, TextParser::default())
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(
+            commands,
+            vec![
+                Command::ChangeCanvas(12),
+                Command::Size(0),
+                Command::GetPixel(0, 1, 2)
+            ]
+        );
+    }
+
+    #[test_case(Response::Help ; "help")]
+    #[test_case(Response::Protocols(vec![ProtocolStatus::Enabled("text".into())]) ; "protocols")]
+    #[test_case(Response::Size(800, 600) ; "size")]
+    #[test_case(Response::GetPixel(12, 34, [0x88, 0x00, 0xff]) ; "get pixel")]
+    #[tokio::test]
+    async fn test_codec_encode_matches_unparse(response: Response) {
+        let mut parser = TextParser::default();
+        let mut expected = Vec::new();
+        parser
+            .unparse(response.clone(), &mut expected)
+            .await
+            .unwrap();
+        let mut buf = BytesMut::new();
+        Encoder::encode(&mut parser, response, &mut buf).unwrap();
+        assert_eq!(&buf[..], &expected[..]);
+    }
 }
@@ -0,0 +1,341 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+
+use crate::{Canvas, RECORDING_BYTES};
+
+/// How recorded frames are named, in local time like the timelapse tools expect. The offset to
+/// UTC keeps the hour that repeats when daylight saving time ends apart.
+const FRAME_NAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S%z";
+/// How frames were named before they had an offset, they are read as local time
+const LEGACY_FRAME_NAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// How long recorded frames are kept, frames are deleted oldest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Delete the oldest frames of any canvas while all frames together take more bytes
+    pub max_bytes: Option<u64>,
+    /// Delete frames older than this
+    pub max_age: Option<Duration>,
+    /// Keep one frame every `.1` of the frames older than `.0`, so long events keep their
+    /// recent minutes in detail and their first days as an overview. Where several apply the
+    /// longest spacing wins.
+    pub thinning: &'static [(Duration, Duration)],
+}
+
+impl Retention {
+    /// How far apart the frames taken `age` ago are kept, in milliseconds
+    fn spacing(&self, age: i64) -> i64 {
+        self.thinning
+            .iter()
+            .filter(|(after, _)| age >= after.as_millis() as i64)
+            .map(|(_, spacing)| spacing.as_millis() as i64)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Recorded {
+    /// relative to the recordings directory
+    name: PathBuf,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    frames: Vec<BTreeMap<i64, Recorded>>,
+    bytes: u64,
+}
+
+impl Index {
+    fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = bytes;
+        RECORDING_BYTES.store(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The frames recorded for every canvas, by the unix timestamp in milliseconds they were taken
+/// at. Every canvas records into a directory of its own, named after its number.
+pub(crate) struct Recordings {
+    dir: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl Recordings {
+    /// Index the frames a previous run left in `dir`, so they can be browsed and are retained
+    /// like new ones
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a directory of a canvas exists but can't be read
+    pub(crate) fn open(dir: PathBuf, canvases: usize) -> io::Result<Recordings> {
+        let mut index = Index {
+            frames: vec![BTreeMap::new(); canvases],
+            bytes: 0,
+        };
+        let mut bytes = 0;
+        for (canvas, frames) in index.frames.iter_mut().enumerate() {
+            let entries = match fs::read_dir(dir.join(canvas.to_string())) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = PathBuf::from(entry.file_name());
+                if let Some(time) = parse_frame_name(&name) {
+                    let recorded = Recorded {
+                        name: PathBuf::from(canvas.to_string()).join(name),
+                        bytes: entry.metadata()?.len(),
+                    };
+                    bytes += recorded.bytes;
+                    frames.insert(time, recorded);
+                }
+            }
+        }
+        index.set_bytes(bytes);
+        Ok(Recordings {
+            dir,
+            index: Mutex::new(index),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().expect("Could not lock recordings")
+    }
+
+    /// Where the frame of `canvas` taken at `time` goes, relative to the recordings directory
+    pub(crate) fn frame_name(canvas: Canvas, time: DateTime<Local>) -> PathBuf {
+        PathBuf::from(canvas.to_string()).join(format!("{}.jpg", time.format(FRAME_NAME_FORMAT)))
+    }
+
+    /// Remember that the frame `name` of `canvas` with `bytes` bytes was taken at `time`. Names
+    /// only keep whole seconds, so does the index, a later frame of the same second replaces the
+    /// earlier one.
+    pub(crate) fn add(&self, canvas: Canvas, time: DateTime<Local>, name: PathBuf, bytes: u64) {
+        let mut index = self.lock();
+        let Some(frames) = index.frames.get_mut(canvas as usize) else {
+            return;
+        };
+        let replaced = frames
+            .insert(time.timestamp() * 1000, Recorded { name, bytes })
+            .map_or(0, |recorded| recorded.bytes);
+        let total = index.bytes - replaced + bytes;
+        index.set_bytes(total);
+    }
+
+    /// When every frame of `canvas` was taken, oldest first. `None` if the canvas doesn't exist.
+    pub(crate) fn times(&self, canvas: Canvas) -> Option<Vec<i64>> {
+        Some(
+            self.lock()
+                .frames
+                .get(canvas as usize)?
+                .keys()
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// The frame of `canvas` taken closest to `time` and when it was taken, `None` if there is
+    /// none
+    pub(crate) fn nearest(&self, canvas: Canvas, time: i64) -> Option<(i64, PathBuf)> {
+        let index = self.lock();
+        let frames = index.frames.get(canvas as usize)?;
+        let before = frames.range(..=time).next_back();
+        let after = frames.range(time..).next();
+        let (time, recorded) = match (before, after) {
+            (Some(before), Some(after)) if after.0 - time < time - before.0 => after,
+            (Some(before), _) => before,
+            (None, after) => after?,
+        };
+        Some((*time, self.dir.join(&recorded.name)))
+    }
+
+    /// Forget the frames `retention` doesn't keep at `now`, a unix timestamp in milliseconds.
+    /// Returns the files to delete.
+    pub(crate) fn prune(&self, now: i64, retention: &Retention) -> Vec<PathBuf> {
+        let mut index = self.lock();
+        let mut removed = Vec::new();
+        for frames in index.frames.iter_mut() {
+            // the first frame of every bucket of `spacing` milliseconds is kept, so the frames
+            // that are kept don't change when older ones go
+            let mut last_bucket = None;
+            frames.retain(|&time, recorded| {
+                let age = now - time;
+                let spacing = retention.spacing(age);
+                let bucket = (spacing != 0).then(|| (spacing, time.div_euclid(spacing)));
+                let too_old = retention
+                    .max_age
+                    .is_some_and(|max_age| age > max_age.as_millis() as i64);
+                let keep = !too_old && (bucket.is_none() || bucket != last_bucket);
+                last_bucket = bucket;
+                if !keep {
+                    removed.push(recorded.clone());
+                }
+                keep
+            });
+        }
+        let mut bytes = index.bytes - removed.iter().map(|recorded| recorded.bytes).sum::<u64>();
+        if let Some(max_bytes) = retention.max_bytes {
+            while bytes > max_bytes {
+                let Some(oldest) = index
+                    .frames
+                    .iter_mut()
+                    .filter(|frames| !frames.is_empty())
+                    .min_by_key(|frames| frames.first_key_value().map(|(time, _)| *time))
+                else {
+                    break;
+                };
+                let (_, recorded) = oldest.pop_first().expect("frames are not empty");
+                bytes -= recorded.bytes;
+                removed.push(recorded);
+            }
+        }
+        index.set_bytes(bytes);
+        removed
+            .into_iter()
+            .map(|recorded| self.dir.join(recorded.name))
+            .collect()
+    }
+}
+
+/// The unix timestamp in milliseconds a frame named `name` was taken at
+fn parse_frame_name(name: &Path) -> Option<i64> {
+    if name.extension()? != "jpg" {
+        return None;
+    }
+    let stem = name.file_stem()?.to_str()?;
+    if let Ok(time) = DateTime::parse_from_str(stem, FRAME_NAME_FORMAT) {
+        return Some(time.timestamp_millis());
+    }
+    let time = NaiveDateTime::parse_from_str(stem, LEGACY_FRAME_NAME_FORMAT).ok()?;
+    Some(
+        Local
+            .from_local_datetime(&time)
+            .earliest()?
+            .timestamp_millis(),
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_and_nearest() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Local.with_ymd_and_hms(2024, 12, 27, 10, 0, 0).unwrap();
+        let second = Local.with_ymd_and_hms(2024, 12, 27, 10, 0, 10).unwrap();
+        let name = Recordings::frame_name(1, first);
+        let offset = first.format("%z");
+        assert_eq!(
+            name,
+            PathBuf::from(format!("1/2024-12-27_10-00-00{offset}.jpg"))
+        );
+        fs::create_dir_all(dir.path().join("1")).unwrap();
+        fs::write(dir.path().join(&name), [0]).unwrap();
+        fs::write(dir.path().join("1/notes.txt"), [0]).unwrap();
+
+        let recordings = Recordings::open(dir.path().to_path_buf(), 2).unwrap();
+        assert_eq!(recordings.times(0), Some(vec![]));
+        assert_eq!(recordings.times(1), Some(vec![first.timestamp_millis()]));
+        assert_eq!(recordings.times(2), None);
+
+        recordings.add(1, second, Recordings::frame_name(1, second), 2);
+        assert_eq!(recordings.lock().bytes, 3);
+        let (first, second) = (first.timestamp_millis(), second.timestamp_millis());
+        assert_eq!(recordings.nearest(1, 0).unwrap().0, first);
+        assert_eq!(recordings.nearest(1, first + 4000).unwrap().0, first);
+        assert_eq!(
+            recordings.nearest(1, first + 6000),
+            Some((
+                second,
+                dir.path()
+                    .join(format!("1/2024-12-27_10-00-10{offset}.jpg"))
+            ))
+        );
+        assert_eq!(recordings.nearest(1, i64::MAX).unwrap().0, second);
+        assert_eq!(recordings.nearest(0, first), None);
+    }
+
+    #[test]
+    fn test_parse_frame_name() {
+        // the hour that repeats when daylight saving time ends in central europe
+        let summer = parse_frame_name(Path::new("2024-10-27_02-30-00+0200.jpg")).unwrap();
+        let winter = parse_frame_name(Path::new("2024-10-27_02-30-00+0100.jpg")).unwrap();
+        assert_eq!(winter - summer, 3600 * 1000);
+        assert_eq!(
+            parse_frame_name(Path::new("2024-12-27_09-00-00+0000.jpg")),
+            Some(1735290000000)
+        );
+
+        let legacy = Local.with_ymd_and_hms(2024, 12, 27, 10, 0, 0).unwrap();
+        assert_eq!(
+            parse_frame_name(Path::new("2024-12-27_10-00-00.jpg")),
+            Some(legacy.timestamp_millis())
+        );
+        assert_eq!(parse_frame_name(Path::new("2024-12-27_10-00-00.png")), None);
+        assert_eq!(parse_frame_name(Path::new("notes.jpg")), None);
+    }
+
+    #[test]
+    fn test_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let recordings = Recordings::open(dir.path().to_path_buf(), 2).unwrap();
+        let start = Local.with_ymd_and_hms(2024, 12, 27, 10, 0, 0).unwrap();
+        // a frame every 10 seconds for 10 minutes on canvas 0, one frame on canvas 1
+        for second in (0..600).step_by(10) {
+            let time = start + Duration::from_secs(second);
+            recordings.add(0, time, Recordings::frame_name(0, time), 10);
+        }
+        let late = start + Duration::from_secs(30);
+        recordings.add(1, late, Recordings::frame_name(1, late), 10);
+        assert_eq!(recordings.lock().bytes, 610);
+        let now = (start + Duration::from_secs(600)).timestamp_millis();
+
+        const THINNING: &[(Duration, Duration)] = &[
+            (Duration::from_secs(60), Duration::from_secs(60)),
+            (Duration::from_secs(300), Duration::from_secs(300)),
+        ];
+        let thinning = Retention {
+            thinning: THINNING,
+            ..Retention::default()
+        };
+        let removed = recordings.prune(now, &thinning);
+        let times: Vec<_> = recordings
+            .times(0)
+            .unwrap()
+            .into_iter()
+            .map(|time| (time - start.timestamp_millis()) / 1000)
+            .collect();
+        // older than 5 minutes one per 5 minutes, older than a minute one per minute
+        assert_eq!(
+            times,
+            [0, 300, 310, 360, 420, 480, 540, 550, 560, 570, 580, 590]
+        );
+        assert_eq!(removed.len(), 48);
+        let name = Recordings::frame_name(0, start + Duration::from_secs(10));
+        assert!(removed.contains(&dir.path().join(name)));
+        assert!(recordings.prune(now, &thinning).is_empty());
+
+        let budget = Retention {
+            max_bytes: Some(100),
+            max_age: Some(Duration::from_secs(590)),
+            ..Retention::default()
+        };
+        let removed = recordings.prune(now, &budget);
+        // the frame at 0 is too old, then the oldest ones of both canvases go
+        assert_eq!(removed.len(), 3);
+        assert_eq!(recordings.times(1), Some(vec![]));
+        assert_eq!(recordings.times(0).unwrap().len(), 10);
+        assert_eq!(recordings.lock().bytes, 100);
+    }
+}
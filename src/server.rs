@@ -0,0 +1,1161 @@
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, File},
+    io::{self, BufWriter},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use futures::never::Never;
+use tokio::{net::TcpListener, sync::watch, task::JoinSet, time::interval};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    archive::ArchiveWriter,
+    clients::{disconnect_client, forget_clients, register_client, ClientId},
+    config::JPEG_UPDATE_INTERVAL,
+    encoder::{spawn_jpeg_encoders, RecordingWriter},
+    flutclient::FlutClient,
+    grid::{self, Flut, Frame},
+    recordings::{Recordings, Retention},
+    stats::{broadcast_stats, client_counter, record_history, Stats, StatsHistory},
+    video::{spawn_video_sinks, VideoSink},
+    wall::{Tile, Wall},
+    webapi::{self, WebApiContext},
+    AsyncResult, Canvas, Coordinate, Protocol, CLIENTS, RUNTIME_LAG_MICROS,
+};
+
+/// How often a recording tries to get a frame no batch of writes finished during
+const RECORDING_SNAPSHOT_ATTEMPTS: usize = 3;
+
+/// How often the runtime lag is sampled, and how many samples the reported maximum covers
+const LAG_SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+const LAG_SAMPLES: usize = 100;
+
+/// How long accepting connections pauses after it failed, like when the process ran out of file
+/// descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often clients that disconnected and don't own a pixel anymore are forgotten
+const CLIENT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Either an address that is bound when the server starts, or a socket that is already bound
+enum Bind {
+    Host(String),
+    Listener(TcpListener),
+}
+
+impl Bind {
+    async fn listen(self) -> io::Result<TcpListener> {
+        match self {
+            Bind::Host(host) => TcpListener::bind(&host).await.inspect_err(|_| {
+                tracing::error!(
+                    "Was unable to bind to {host}, please check if a different process is bound"
+                )
+            }),
+            Bind::Listener(listener) => Ok(listener),
+        }
+    }
+}
+
+/// Builds a flurry server from canvases and listeners, nothing is bound or spawned until
+/// [`Server::start`] is called.
+///
+/// ```no_run
+/// # async fn run() -> flurry::AsyncResult<()> {
+/// let server = flurry::Server::new()
+///     .canvas(flurry::grid::Flut::init(800, 600, 0xff_00_ff_ff))
+///     .flut_host("127.0.0.1:0")
+///     .start()
+///     .await?;
+/// println!("pixelflut on {:?}", server.flut_addrs());
+/// server.wait().await
+/// # }
+/// ```
+pub struct Server {
+    grids: Vec<Flut<u32>>,
+    walls: Vec<Wall>,
+    flut_binds: Vec<(Bind, Option<Protocol>)>,
+    web_bind: Option<Bind>,
+    recordings: Option<(PathBuf, Duration)>,
+    retention: Retention,
+    archives: Option<(PathBuf, Duration)>,
+    video_sinks: Vec<VideoSink>,
+    jpeg_interval: Duration,
+    admin_token: Option<Arc<str>>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            grids: Vec::new(),
+            walls: Vec::new(),
+            flut_binds: Vec::new(),
+            web_bind: None,
+            recordings: None,
+            retention: Retention::default(),
+            archives: None,
+            video_sinks: Vec::new(),
+            jpeg_interval: JPEG_UPDATE_INTERVAL,
+            admin_token: None,
+        }
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    /// Add a canvas, canvases are numbered in the order they are added
+    pub fn canvas(mut self, grid: Flut<u32>) -> Self {
+        self.grids.push(grid);
+        self
+    }
+
+    /// Add a wall made of tiles of the canvases, walls are numbered after every canvas in the
+    /// order they are added
+    pub fn wall(mut self, tiles: &[Tile]) -> Self {
+        self.walls.push(Wall::new(tiles));
+        self
+    }
+
+    /// Accept pixelflut clients on `host`, the protocol is detected per connection
+    pub fn flut_host(mut self, host: impl Into<String>) -> Self {
+        self.flut_binds.push((Bind::Host(host.into()), None));
+        self
+    }
+
+    /// Accept pixelflut clients on a socket that is already bound
+    pub fn flut_listener(mut self, listener: TcpListener) -> Self {
+        self.flut_binds.push((Bind::Listener(listener), None));
+        self
+    }
+
+    /// Accept pixelflut clients on `host` that always start in `protocol`
+    pub fn forced_protocol_host(mut self, host: impl Into<String>, protocol: Protocol) -> Self {
+        self.flut_binds
+            .push((Bind::Host(host.into()), Some(protocol)));
+        self
+    }
+
+    /// Accept pixelflut clients on an already bound socket that always start in `protocol`
+    pub fn forced_protocol_listener(mut self, listener: TcpListener, protocol: Protocol) -> Self {
+        self.flut_binds
+            .push((Bind::Listener(listener), Some(protocol)));
+        self
+    }
+
+    /// Serve the web interface on `host`, without this there is no web interface
+    pub fn web_host(mut self, host: impl Into<String>) -> Self {
+        self.web_bind = Some(Bind::Host(host.into()));
+        self
+    }
+
+    /// Serve the web interface on a socket that is already bound
+    pub fn web_listener(mut self, listener: TcpListener) -> Self {
+        self.web_bind = Some(Bind::Listener(listener));
+        self
+    }
+
+    /// Save a frame of every canvas to `dir` every `interval` it changed
+    pub fn recordings(mut self, dir: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.recordings = Some((dir.into(), interval));
+        self
+    }
+
+    /// Delete recorded frames `retention` doesn't keep, frames are kept forever without this
+    pub fn recording_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Append the user layer of every canvas to the lossless archive `{dir}/{canvas}.flurry`
+    /// every `interval` it changed, see [`crate::archive`]
+    pub fn archives(mut self, dir: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.archives = Some((dir.into(), interval));
+        self
+    }
+
+    /// Write raw frames of a canvas to a file, named pipe or program, see [`VideoSink`]
+    pub fn video_sink(mut self, sink: VideoSink) -> Self {
+        self.video_sinks.push(sink);
+        self
+    }
+
+    /// How often the jpeg the web interface streams is updated
+    pub fn jpeg_interval(mut self, interval: Duration) -> Self {
+        self.jpeg_interval = interval;
+        self
+    }
+
+    /// Allow admin requests to the web interface that carry `token` as a bearer token, without
+    /// it every admin request is refused
+    pub fn admin_token(mut self, token: impl Into<Arc<str>>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Bind every listener and spawn the tasks that make up the server.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if one of the hosts can't be bound or a thread can't
+    /// be spawned, and `InvalidInput` if a wall or video sink uses a canvas that doesn't exist,
+    /// a wall is wider or taller than a coordinate can address or there are more than 256
+    /// canvases and walls
+    pub async fn start(self) -> io::Result<ServerHandle> {
+        let mut tiles = self.walls.iter().flat_map(|wall| wall.tiles());
+        let mut sinks = self.video_sinks.iter();
+        let mut wall_sizes = self.walls.iter().map(|wall| wall.get_size());
+        if self.grids.len() + self.walls.len() > 256
+            || tiles.any(|tile| tile.canvas as usize >= self.grids.len())
+            || wall_sizes.any(|(size_x, size_y)| size_x.max(size_y) > Coordinate::MAX as usize)
+            || sinks.any(|sink| sink.canvas as usize >= self.grids.len())
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let grids: Arc<[Flut<u32>]> = self.grids.into();
+        let walls: Arc<[Wall]> = self.walls.into();
+        let shutdown = CancellationToken::new();
+        let mut tasks = JoinSet::new();
+
+        let mut flut_addrs = Vec::with_capacity(self.flut_binds.len());
+        let mut flut_listeners = Vec::with_capacity(self.flut_binds.len());
+        for (bind, protocol) in self.flut_binds {
+            let listener = bind.listen().await?;
+            let addr = listener.local_addr()?;
+            match protocol {
+                Some(protocol) => {
+                    tracing::info!("Started TCP listener on {addr} with {protocol:?}")
+                }
+                None => tracing::info!("Started TCP listener on {addr}"),
+            }
+            flut_addrs.push(addr);
+            flut_listeners.push((listener, protocol));
+        }
+        let web_listener = match self.web_bind {
+            Some(bind) => Some(bind.listen().await?),
+            None => None,
+        };
+        let web_addr = match &web_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+
+        let mut encoders = spawn_jpeg_encoders(
+            grids.clone(),
+            walls.clone(),
+            self.jpeg_interval,
+            shutdown.clone(),
+        )?;
+        encoders.extend(spawn_video_sinks(
+            grids.clone(),
+            &self.video_sinks,
+            shutdown.clone(),
+        )?);
+        let recordings = match self.recordings {
+            Some((dir, interval)) => Some((
+                Arc::new(Recordings::open(dir.clone(), grids.len())?),
+                RecordingWriter::spawn(dir)?,
+                interval,
+            )),
+            None => None,
+        };
+
+        let archives = match self.archives {
+            Some((dir, interval)) => {
+                create_dir_all(&dir)?;
+                let writers = (0..grids.len())
+                    .map(|canvas| ArchiveWriter::open(&archive_path(&dir, canvas as Canvas)))
+                    .collect::<io::Result<Vec<_>>>()?;
+                Some((dir, writers, interval))
+            }
+            None => None,
+        };
+
+        // clients only get an id if there is a canvas to attribute their pixels on
+        let attributed = grids.iter().any(|grid| grid.attribution().is_some());
+        for (listener, protocol) in flut_listeners {
+            tasks.spawn(handle_flut(
+                listener,
+                grids.clone(),
+                walls.clone(),
+                protocol,
+                attributed,
+            ));
+        }
+        if attributed {
+            tasks.spawn(prune_clients(grids.clone()));
+        }
+        tasks.spawn(measure_runtime_lag());
+        let recorded = recordings.as_ref().map(|(recorded, ..)| recorded.clone());
+        if let Some((recorded, writer, interval)) = recordings {
+            tasks.spawn(save_image_frames(
+                grids.clone(),
+                recorded,
+                self.retention,
+                writer,
+                interval,
+            ));
+        }
+        let archive_dir = archives.as_ref().map(|(dir, ..)| dir.clone());
+        if let Some((_, writers, interval)) = archives {
+            tasks.spawn(archive_frames(grids.clone(), writers, interval));
+        }
+        if let Some(listener) = web_listener {
+            let (stats_sender, stats) = watch::channel(Arc::new(Stats::collect(&grids)));
+            tasks.spawn(broadcast_stats(grids.clone(), stats_sender));
+            let history = Arc::new(Mutex::new(StatsHistory::default()));
+            tasks.spawn(record_history(history.clone()));
+            tasks.spawn(webapi::serve(
+                WebApiContext {
+                    grids: grids.clone(),
+                    walls,
+                    views: Arc::default(),
+                    stats,
+                    history,
+                    recordings: recorded,
+                    archives: archive_dir,
+                    admin_token: self.admin_token,
+                },
+                listener,
+            ));
+        }
+
+        Ok(ServerHandle {
+            flut_addrs,
+            web_addr,
+            grids,
+            shutdown,
+            tasks,
+            encoders,
+        })
+    }
+}
+
+/// A running server, dropping it stops the server
+pub struct ServerHandle {
+    flut_addrs: Vec<SocketAddr>,
+    web_addr: Option<SocketAddr>,
+    grids: Arc<[Flut<u32>]>,
+    shutdown: CancellationToken,
+    tasks: JoinSet<AsyncResult<Never>>,
+    encoders: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The addresses the pixelflut listeners are bound to, in the order they were added
+    pub fn flut_addrs(&self) -> &[SocketAddr] {
+        &self.flut_addrs
+    }
+
+    /// The address the web interface is bound to, if it has one
+    pub fn web_addr(&self) -> Option<SocketAddr> {
+        self.web_addr
+    }
+
+    pub fn grids(&self) -> Arc<[Flut<u32>]> {
+        self.grids.clone()
+    }
+
+    /// A token that stops the server when cancelled, for shutting down while someone is waiting
+    /// on [`ServerHandle::wait`]
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Stop every listener, connection and background task
+    pub async fn shutdown(mut self) {
+        self.stop().await;
+    }
+
+    async fn stop(&mut self) {
+        self.shutdown.cancel();
+        self.tasks.shutdown().await;
+        let encoders = std::mem::take(&mut self.encoders);
+        let joined = tokio::task::spawn_blocking(move || {
+            for encoder in encoders {
+                let _ = encoder.join();
+            }
+        });
+        let _ = joined.await;
+    }
+
+    /// Run until the server is shut down through its token, or until one of its tasks fails.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the error of the first task that stopped
+    pub async fn wait(mut self) -> AsyncResult<()> {
+        let res = tokio::select! {
+            _ = self.shutdown.cancelled() => Ok(()),
+            Some(res) = self.tasks.join_next() => match res {
+                Ok(Err(err)) => Err(err),
+                Err(err) => Err(err.into()),
+            },
+        };
+        self.stop().await;
+        res
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        // the encoder threads aren't tasks, they have to be told to stop
+        self.shutdown.cancel();
+    }
+}
+
+/// This function starts a timer that saves the current grid state every `duration`.
+/// These images may then be used for moderation or timelapses
+///
+/// Frames are encoded on the blocking pool and written by the dedicated `writer` thread, every
+/// canvas into a directory of its own. Canvases that look the same as in their last frame are
+/// skipped. Queued frames are added to `recordings`, the ones `retention` doesn't keep are
+/// deleted.
+///
+/// # Errors
+///
+/// This function will return an error if a frame can't be encoded or the writer thread is gone,
+/// frames that can't be written are only logged by the writer
+async fn save_image_frames(
+    grids: Arc<[grid::Flut<u32>]>,
+    recordings: Arc<Recordings>,
+    retention: Retention,
+    writer: RecordingWriter,
+    duration: Duration,
+) -> AsyncResult<Never> {
+    let mut timer = interval(duration);
+    let mut last_hashes = vec![None; grids.len()];
+    loop {
+        timer.tick().await;
+        for (canvas, last_hash) in last_hashes.iter_mut().enumerate() {
+            let grids = grids.clone();
+            let previous = *last_hash;
+            let (hash, jpg) = tokio::task::spawn_blocking(move || {
+                let mut frame = Frame::new();
+                grids[canvas].snapshot_at_boundary(&mut frame, RECORDING_SNAPSHOT_ATTEMPTS);
+                let hash = frame.content_hash();
+                if previous == Some(hash) {
+                    return Ok((hash, None));
+                }
+                let mut jpgbuf = Vec::new();
+                frame
+                    .encode_jpg(50, &mut jpgbuf)
+                    .map(|_| (hash, Some(jpgbuf)))
+            })
+            .await??;
+            let Some(jpg) = jpg else {
+                continue;
+            };
+            let time = chrono::Local::now();
+            let name = Recordings::frame_name(canvas as Canvas, time);
+            let bytes = jpg.len() as u64;
+            if writer.write(name.clone(), jpg)? {
+                recordings.add(canvas as Canvas, time, name, bytes);
+                *last_hash = Some(hash);
+            }
+        }
+        let stale = recordings.prune(chrono::Utc::now().timestamp_millis(), &retention);
+        if !stale.is_empty() {
+            tokio::task::spawn_blocking(move || {
+                for path in stale {
+                    if let Err(err) = std::fs::remove_file(&path) {
+                        tracing::warn!("Could not delete recording {path:?}: {err}");
+                    }
+                }
+            })
+            .await?;
+        }
+    }
+}
+
+/// Where the archive of `canvas` is kept in `dir`
+pub(crate) fn archive_path(dir: &Path, canvas: Canvas) -> PathBuf {
+    dir.join(format!("{canvas}.flurry"))
+}
+
+/// Append the user layer of every canvas to its archive every `duration`, on the blocking pool
+///
+/// # Errors
+///
+/// This function will return an error if an archive can't be written to
+async fn archive_frames(
+    grids: Arc<[grid::Flut<u32>]>,
+    writers: Vec<ArchiveWriter<BufWriter<File>>>,
+    duration: Duration,
+) -> AsyncResult<Never> {
+    let mut timer = interval(duration);
+    let mut writers: Vec<_> = writers.into_iter().map(Some).collect();
+    loop {
+        timer.tick().await;
+        for (canvas, slot) in writers.iter_mut().enumerate() {
+            let grids = grids.clone();
+            let mut writer = slot.take().expect("writer is put back after every append");
+            let writer = tokio::task::spawn_blocking(move || {
+                let mut frame = Frame::new();
+                grids[canvas].snapshot_user_layer(&mut frame);
+                let time = chrono::Utc::now().timestamp_millis();
+                writer.append(time, &frame).map(|_| writer)
+            })
+            .await??;
+            *slot = Some(writer);
+        }
+    }
+}
+
+/// Measure how late a task gets woken up, if something blocks the runtime this goes up.
+/// The highest lag of the last `LAG_SAMPLES` samples is published in `RUNTIME_LAG_MICROS`.
+async fn measure_runtime_lag() -> AsyncResult<Never> {
+    let mut samples = [0; LAG_SAMPLES];
+    for idx in (0..LAG_SAMPLES).cycle() {
+        let start = Instant::now();
+        tokio::time::sleep(LAG_SAMPLE_INTERVAL).await;
+        let lag = start.elapsed().saturating_sub(LAG_SAMPLE_INTERVAL);
+        samples[idx] = lag.as_micros() as u64;
+        let max = samples.iter().max().copied().unwrap_or_default();
+        RUNTIME_LAG_MICROS.store(max, Ordering::Relaxed);
+    }
+    unreachable!("cycle never ends")
+}
+
+/// Forget the clients that disconnected once no attributed pixel refers to them anymore
+async fn prune_clients(grids: Arc<[grid::Flut<u32>]>) -> AsyncResult<Never> {
+    let mut interval = interval(CLIENT_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut owners = HashSet::new();
+        for grid in grids.iter() {
+            if let Some(attribution) = grid.attribution() {
+                attribution.collect_owners(&mut owners);
+            }
+        }
+        forget_clients(&owners);
+    }
+}
+
+/// Handle connections made to the socket, keeps a set of the currently active connections and
+/// cleans up the finished ones to stop a memory leak. Dropping the task closes every connection.
+///
+/// If `protocol` is set, every connection starts in that protocol instead of detecting it. If
+/// `attributed` is set, every connection is registered as a client its pixels are attributed to.
+async fn handle_flut(
+    flut_listener: TcpListener,
+    grids: Arc<[grid::Flut<u32>]>,
+    walls: Arc<[Wall]>,
+    protocol: Option<Protocol>,
+    attributed: bool,
+) -> AsyncResult<Never> {
+    let mut handles = JoinSet::new();
+    loop {
+        let (mut socket, addr) = match flut_listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!("Could not accept a connection: {err}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        while handles.try_join_next().is_some() {}
+        let grids = grids.clone();
+        let walls = walls.clone();
+        handles.spawn(async move {
+            let (reader, writer) = socket.split();
+            let mut connection = FlutClient::new(reader, writer, grids);
+            connection.set_walls(walls);
+            connection.count_pixels_in(client_counter(addr.ip()));
+            let client = attributed.then(|| register_client(addr));
+            if let Some(client) = client {
+                connection.identify(client);
+            }
+            if let Some(protocol) = &protocol {
+                connection.force_protocol(protocol);
+            }
+            CLIENTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let _connected = Connected { client };
+            connection.process_socket().await
+        });
+    }
+}
+
+/// Counts a connection as a client until it is dropped, also when its task is aborted
+struct Connected {
+    client: Option<ClientId>,
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        CLIENTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        if let Some(client) = self.client {
+            disconnect_client(client);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::grid::Grid;
+
+    #[tokio::test]
+    async fn test_start_on_port_zero() {
+        let server = Server::new()
+            .canvas(Flut::init(800, 600, 0))
+            .flut_host("127.0.0.1:0")
+            .web_host("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(server.flut_addrs()[0].port(), 0);
+        assert_ne!(server.web_addr().unwrap().port(), 0);
+
+        let mut stream = TcpStream::connect(server.flut_addrs()[0]).await.unwrap();
+        stream.write_all(b"PX 1 2 123456\nSIZE\n").await.unwrap();
+        let mut buf = [0; 13];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SIZE 800 600\n");
+        assert_eq!(server.grids()[0].get(1, 2), Some(0x12_34_56_ff));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_prebound_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new()
+            .canvas(Flut::init(800, 600, 0))
+            .forced_protocol_listener(listener, Protocol::Binary)
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.flut_addrs(), &[addr]);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0x73, 0x00]).await.unwrap();
+        assert_eq!(stream.read_u16().await.unwrap(), 800);
+        assert_eq!(stream.read_u16().await.unwrap(), 600);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let res = Server::new().flut_host(addr.to_string()).start().await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_token() {
+        let server = Server::new()
+            .canvas(Flut::init(8, 8, 0))
+            .flut_host("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.flut_addrs()[0];
+        server.shutdown_token().cancel();
+        server.wait().await.unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    /// Send a bare HTTP/1.1 GET and return the whole response
+    pub(crate) async fn http_get(addr: SocketAddr, path: &str) -> Vec<u8> {
+        http_request(addr, "GET", path, None).await
+    }
+
+    /// Send a bare HTTP/1.1 request without a body, optionally with a bearer token, and return
+    /// the whole response
+    pub(crate) async fn http_request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+    ) -> Vec<u8> {
+        http_send(addr, method, path, token, b"").await
+    }
+
+    /// Send a bare HTTP/1.1 request with a json `body` and return the whole response
+    pub(crate) async fn http_send(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let auth = match token {
+            Some(token) => format!("Authorization: Bearer {token}\r\n"),
+            None => String::new(),
+        };
+        let length = body.len();
+        stream
+            .write_all(
+                format!(
+                    "{method} {path} HTTP/1.1\r\nHost: flurry\r\n{auth}Content-Type: application/json\r\nContent-Length: {length}\r\nConnection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_reset_canvas() {
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0x12_34_56_ff))
+            .web_host("127.0.0.1:0")
+            .admin_token("secret")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        let grid = &server.grids()[0];
+        grid.set(0, 0, 0);
+        grid.set(3, 1, 0);
+
+        let response = http_request(addr, "POST", "/canvas/0/reset?x=2", None).await;
+        assert!(response.starts_with(b"HTTP/1.1 401"));
+        let response = http_request(addr, "POST", "/canvas/0/reset?x=2", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+        assert_eq!(grid.get(0, 0), Some(0));
+        assert_eq!(grid.get(3, 1), Some(0x12_34_56_ff));
+        let response = http_request(addr, "POST", "/canvas/0/reset", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+        assert_eq!(grid.get(0, 0), Some(0x12_34_56_ff));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_resize_canvas() {
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .flut_host("127.0.0.1:0")
+            .web_host("127.0.0.1:0")
+            .admin_token("secret")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        server.grids()[0].set(0, 0, 0x12_34_56_ff);
+
+        let path = "/canvas/0/resize?width=6&height=3&anchor=bottom-right";
+        let response = http_request(addr, "POST", path, None).await;
+        assert!(response.starts_with(b"HTTP/1.1 401"));
+        let response = http_request(addr, "POST", path, Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+        assert_eq!(server.grids()[0].get(2, 1), Some(0x12_34_56_ff));
+        let response = http_request(
+            addr,
+            "POST",
+            "/canvas/0/resize?width=0&height=3",
+            Some("secret"),
+        )
+        .await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response = http_request(
+            addr,
+            "POST",
+            "/canvas/1/resize?width=6&height=3",
+            Some("secret"),
+        )
+        .await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+
+        let mut client = TcpStream::connect(server.flut_addrs()[0]).await.unwrap();
+        client.write_all(b"SIZE\n").await.unwrap();
+        let mut buf = [0; 64];
+        let read = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..read], b"SIZE 6 3\n");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_protected_regions() {
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .web_host("127.0.0.1:0")
+            .admin_token("secret")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        let rect = br#"{"type":"rect","x":1,"y":0,"width":2,"height":2}"#;
+
+        let response = http_send(addr, "POST", "/canvas/0/protected", None, rect).await;
+        assert!(response.starts_with(b"HTTP/1.1 401"));
+        let response = http_send(addr, "POST", "/canvas/0/protected", Some("secret"), rect).await;
+        assert!(response.ends_with(br#"{"id":0}"#));
+        assert!(server.grids()[0].protection().is_protected(1, 1));
+
+        let response = http_get(addr, "/canvas/0/protected").await;
+        assert!(response.ends_with(br#"[{"id":0,"type":"rect","x":1,"y":0,"width":2,"height":2}]"#));
+
+        let mut png = Vec::new();
+        image::RgbImage::from_pixel(1, 1, image::Rgb([255, 255, 255]))
+            .write_with_encoder(image::codecs::png::PngEncoder::new(&mut png))
+            .unwrap();
+        let response = http_send(
+            addr,
+            "PUT",
+            "/canvas/0/protected/mask?x=3&y=1",
+            Some("secret"),
+            &png,
+        )
+        .await;
+        assert!(response.ends_with(br#"{"id":1}"#));
+        assert!(server.grids()[0].protection().is_protected(3, 1));
+
+        let response = http_request(addr, "DELETE", "/canvas/0/protected/0", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+        assert!(!server.grids()[0].protection().is_protected(1, 1));
+        let response = http_request(addr, "DELETE", "/canvas/0/protected/0", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_activity_layers() {
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0).with_activity())
+            .canvas(Flut::init(4, 2, 0))
+            .web_host("127.0.0.1:0")
+            .admin_token("secret")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        server.grids()[0].set(3, 1, 0x12_34_56_ff);
+
+        for path in ["/canvas/0/heatmap.png", "/canvas/0/age.png"] {
+            let response = http_get(addr, path).await;
+            let text = String::from_utf8_lossy(&response);
+            assert!(text.starts_with("HTTP/1.1 200 OK"));
+            assert!(text.contains("image/png"));
+        }
+        let response = http_get(addr, "/canvas/1/heatmap.png").await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+
+        let response = http_request(addr, "POST", "/canvas/0/activity/reset", None).await;
+        assert!(response.starts_with(b"HTTP/1.1 401"));
+        let response = http_request(addr, "POST", "/canvas/0/activity/reset", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+        assert_eq!(server.grids()[0].activity().unwrap().writes(7), 0);
+        let response = http_request(addr, "POST", "/canvas/1/activity/reset", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_pixel_owner() {
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0).with_attribution())
+            .flut_host("127.0.0.1:0")
+            .web_host("127.0.0.1:0")
+            .admin_token("secret")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        let mut client = TcpStream::connect(server.flut_addrs()[0]).await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        client.write_all(b"PX 1 1 ff0000\nSIZE\n").await.unwrap();
+        // wait for the answer to SIZE, so the pixel before it was set
+        let mut buf = [0; 64];
+        let _ = client.read(&mut buf).await.unwrap();
+
+        let response = http_request(addr, "GET", "/canvas/0/pixel/1/1", Some("secret")).await;
+        let text = String::from_utf8_lossy(&response);
+        assert!(text.starts_with("HTTP/1.1 200 OK"));
+        assert!(text.contains(&format!("\"address\":\"{client_addr}\"")));
+        let response = http_request(addr, "GET", "/canvas/0/pixel/0/0", Some("secret")).await;
+        assert!(response.ends_with(b"null"));
+        let response = http_request(addr, "GET", "/canvas/0/pixel/4/0", Some("secret")).await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+        let response = http_get(addr, "/canvas/0/pixel/1/1").await;
+        assert!(response.starts_with(b"HTTP/1.1 401"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_stats_history() {
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .web_host("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let response = http_get(server.web_addr().unwrap(), "/stats/history").await;
+        let text = String::from_utf8_lossy(&response);
+        assert!(text.starts_with("HTTP/1.1 200 OK"));
+        assert!(text.contains("application/json"));
+        assert!(text.ends_with(r#"{"seconds":[],"minutes":[]}"#));
+        let response = http_get(server.web_addr().unwrap(), "/stats/history?range=minutes").await;
+        assert!(String::from_utf8_lossy(&response).ends_with("\r\n\r\n[]"));
+        let response = http_get(server.web_addr().unwrap(), "/stats/history?range=hours").await;
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 400"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_image_stream_pushes_frames() {
+        let server = Server::new()
+            .canvas(Flut::init(8, 8, 0))
+            .web_host("127.0.0.1:0")
+            .jpeg_interval(Duration::from_millis(1))
+            .start()
+            .await
+            .unwrap();
+        let mut stream = TcpStream::connect(server.web_addr().unwrap())
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /imgstream?canvas=0 HTTP/1.1\r\nHost: flurry\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        // wait for a part header followed by the start of a jpeg
+        let contains =
+            |response: &[u8], needle: &[u8]| response.windows(needle.len()).any(|w| w == needle);
+        while !(contains(&response, b"content-type: image/jpeg")
+            && contains(&response, b"\xff\xd8\xff"))
+        {
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(read, 0);
+            response.extend_from_slice(&buf[..read]);
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_wall() {
+        let tile = |canvas, x| Tile {
+            canvas,
+            x,
+            y: 0,
+            src_x: 0,
+            src_y: 0,
+            width: 4,
+            height: 2,
+        };
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .canvas(Flut::init(4, 2, 0))
+            .wall(&[tile(0, 0), tile(1, 4)])
+            .flut_host("127.0.0.1:0")
+            .web_host("127.0.0.1:0")
+            .jpeg_interval(Duration::from_millis(1))
+            .start()
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(server.flut_addrs()[0]).await.unwrap();
+        client
+            .write_all(b"CANVAS 2\nPX 5 1 123456\nPX 5 1\nSIZE\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 23];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"PX 5 1 123456\nSIZE 8 2\n");
+        assert_eq!(server.grids()[1].get(1, 1), Some(0x12_34_56_ff));
+
+        let addr = server.web_addr().unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /imgstream?canvas=2 HTTP/1.1\r\nHost: flurry\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 15];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 OK");
+        let response = http_get(addr, "/imgstream?canvas=3").await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+        server.shutdown().await;
+
+        let missing = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .wall(&[tile(1, 0)]);
+        assert!(missing.start().await.is_err());
+        let too_wide = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .wall(&[tile(0, Coordinate::MAX as usize - 3)]);
+        assert!(too_wide.start().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_image_stream_views() {
+        let server = Server::new()
+            .canvas(Flut::init(8, 8, 0))
+            .web_host("127.0.0.1:0")
+            .jpeg_interval(Duration::from_millis(1))
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        for (query, status) in [
+            ("canvas=1", "404"),
+            ("canvas=0&x=8", "400"),
+            ("canvas=0&x=4&w=5", "400"),
+            ("canvas=0&h=0", "400"),
+            ("canvas=0&scale=17", "400"),
+            ("canvas=0&w=-1", "400"),
+        ] {
+            let response = http_get(addr, &format!("/imgstream?{query}")).await;
+            let expected = format!("HTTP/1.1 {status}");
+            assert!(response.starts_with(expected.as_bytes()), "{query}");
+        }
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /imgstream?canvas=0&x=4&y=4&scale=2 HTTP/1.1\r\nHost: flurry\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 15];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 OK");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_canvas_list() {
+        let tile = Tile {
+            canvas: 0,
+            x: 0,
+            y: 0,
+            src_x: 0,
+            src_y: 0,
+            width: 2,
+            height: 2,
+        };
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0).with_name("main").with_scale(2))
+            .canvas(Flut::init(8, 6, 0))
+            .wall(&[tile])
+            .web_host("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        let response = http_get(addr, "/canvases").await;
+        assert!(response.ends_with(concat!(
+            r#"[{"id":0,"name":"main","kind":"canvas","width":4,"height":2,"scale":2,"pixels":0,"pixel_rate":0},"#,
+            r#"{"id":1,"name":"canvas 1","kind":"canvas","width":8,"height":6,"scale":1,"pixels":0,"pixel_rate":0},"#,
+            r#"{"id":2,"name":"wall 0","kind":"wall","width":2,"height":2,"scale":1,"pixels":null,"pixel_rate":null}]"#
+        ).as_bytes()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /imgstream?canvas=2&thumbnail=true HTTP/1.1\r\nHost: flurry\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 15];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 OK");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .web_host("127.0.0.1:0")
+            .recordings(dir.path(), Duration::from_millis(10))
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        assert!(http_get(addr, "/history/1")
+            .await
+            .starts_with(b"HTTP/1.1 404"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let time = loop {
+            let response = http_get(addr, "/history/0").await;
+            let body = String::from_utf8_lossy(&response);
+            let times = body.split("\r\n\r\n").nth(1).unwrap();
+            let times: Vec<i64> = serde_json::from_str(times).unwrap();
+            if let Some(time) = times.first() {
+                break *time;
+            }
+            assert!(Instant::now() < deadline, "no frame was recorded");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            // the frame may still be waiting for the disk
+            let response = http_get(addr, &format!("/history/0/frame?time={}", time + 1)).await;
+            if response.starts_with(b"HTTP/1.1 200") {
+                let header = format!("x-frame-time: {time}\r\n");
+                assert!(String::from_utf8_lossy(&response).contains(&header));
+                break;
+            }
+            assert!(Instant::now() < deadline, "frame never got to the disk");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::new()
+            .canvas(Flut::init(4, 2, 0))
+            .canvas(Flut::init(1, 1, 0))
+            .web_host("127.0.0.1:0")
+            .archives(dir.path(), Duration::from_millis(10))
+            .admin_token("secret")
+            .start()
+            .await
+            .unwrap();
+        let addr = server.web_addr().unwrap();
+        server.grids()[0].set(3, 1, 0x12_34_56_ff);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let archive = loop {
+            let response = http_get(addr, "/archive/0").await;
+            let start = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let archive = response[start..].to_vec();
+            let mut reader = std::io::Cursor::new(&archive);
+            let written = crate::archive::read_frame(&mut reader, i64::MAX).unwrap();
+            if written.is_some_and(|(_, frame)| frame.pixels()[7] == 0x12_34_56_ff) {
+                break archive;
+            }
+            assert!(Instant::now() < deadline, "the change was never archived");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let png = http_get(addr, "/archive/0/frame.png").await;
+        assert!(png.starts_with(b"HTTP/1.1 200"));
+
+        let path = "/canvas/1/import";
+        assert!(http_send(addr, "POST", path, None, &archive)
+            .await
+            .starts_with(b"HTTP/1.1 401"));
+        assert!(http_send(addr, "POST", path, Some("secret"), b"nope")
+            .await
+            .starts_with(b"HTTP/1.1 400"));
+        assert!(http_send(addr, "POST", path, Some("secret"), &archive)
+            .await
+            .starts_with(b"HTTP/1.1 204"));
+        assert_eq!(server.grids()[1].get_size(), (4, 2));
+        assert_eq!(server.grids()[1].get(3, 1), Some(0x12_34_56_ff));
+        server.shutdown().await;
+    }
+}
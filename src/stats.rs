@@ -0,0 +1,393 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::never::Never;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::interval};
+
+use crate::{
+    config::{
+        HISTORY_MINUTES, HISTORY_SECONDS, STATS_INTERVAL, STATS_LEADERBOARD_SIZE,
+        STATS_TRACKED_CLIENTS,
+    },
+    grid::Flut,
+    AsyncResult, Canvas, BYTES, CLIENTS, COUNTER, ENCODE_MICROS, PARSE_ERRORS, RECORDING_BYTES,
+    REJECTED, RUNTIME_LAG_MICROS,
+};
+
+/// Pixels set per address, kept after disconnecting so the leaderboard survives reconnects.
+/// At most [`STATS_TRACKED_CLIENTS`] are kept unless more are connected at once.
+static CLIENT_COUNTERS: LazyLock<Mutex<HashMap<IpAddr, Arc<AtomicU64>>>> =
+    LazyLock::new(Mutex::default);
+
+/// Keyed with random keys for every run, so the public ids of the leaderboard can't be turned
+/// back into addresses by hashing every address
+static CLIENT_ID_HASHER: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+
+/// The counter the pixels of every connection from `ip` are added to
+pub fn client_counter(ip: IpAddr) -> Arc<AtomicU64> {
+    let mut counters = CLIENT_COUNTERS
+        .lock()
+        .expect("Could not lock client counters");
+    if counters.len() >= STATS_TRACKED_CLIENTS && !counters.contains_key(&ip) {
+        // only the map holds the counters of disconnected addresses
+        let forgotten = counters
+            .iter()
+            .filter(|(_, pixels)| Arc::strong_count(pixels) == 1)
+            .min_by_key(|(_, pixels)| pixels.load(Ordering::Relaxed))
+            .map(|(ip, _)| *ip);
+        if let Some(forgotten) = forgotten {
+            counters.remove(&forgotten);
+        }
+    }
+    counters.entry(ip).or_default().clone()
+}
+
+/// The id `ip` is shown with on the leaderboard, stable for the lifetime of the server
+fn client_id(ip: IpAddr) -> String {
+    format!("{:08x}", CLIENT_ID_HASHER.hash_one(ip) as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topic {
+    Global,
+    Canvas,
+    Leaderboard,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GlobalStats {
+    pub clients: u64,
+    pub pixels: u64,
+    pub encode_micros: u64,
+    pub runtime_lag_micros: u64,
+    /// pixels that were not set because they are protected
+    pub rejected: u64,
+    /// bytes the recorded frames take on disk
+    pub recording_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CanvasStats {
+    pub canvas: Canvas,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: u64,
+    /// pixels set per second, averaged over the last second
+    pub pixel_rate: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LeaderboardEntry {
+    /// a hash of the address, the leaderboard is public
+    pub client: String,
+    pub pixels: u64,
+}
+
+/// Everything the stats producer knows at one point in time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub global: GlobalStats,
+    pub canvases: Vec<CanvasStats>,
+    pub leaderboard: Vec<LeaderboardEntry>,
+}
+
+/// The part of [`Stats`] a subscriber asked for, topics it did not ask for are left out
+#[derive(Serialize)]
+struct StatsMessage<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    global: Option<&'a GlobalStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canvases: Option<&'a [CanvasStats]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leaderboard: Option<&'a [LeaderboardEntry]>,
+}
+
+impl Stats {
+    pub fn collect(grids: &[Flut<u32>]) -> Stats {
+        let global = GlobalStats {
+            clients: CLIENTS.load(Ordering::Relaxed),
+            pixels: COUNTER.load(Ordering::Relaxed),
+            encode_micros: ENCODE_MICROS.load(Ordering::Relaxed),
+            runtime_lag_micros: RUNTIME_LAG_MICROS.load(Ordering::Relaxed),
+            rejected: REJECTED.load(Ordering::Relaxed),
+            recording_bytes: RECORDING_BYTES.load(Ordering::Relaxed),
+        };
+        let canvases = grids
+            .iter()
+            .enumerate()
+            .map(|(canvas, grid)| {
+                let (width, height) = grid.get_size();
+                CanvasStats {
+                    canvas: canvas as Canvas,
+                    width,
+                    height,
+                    pixels: grid.pixels(),
+                    pixel_rate: 0,
+                }
+            })
+            .collect();
+        let mut leaderboard: Vec<_> = CLIENT_COUNTERS
+            .lock()
+            .expect("Could not lock client counters")
+            .iter()
+            .map(|(client, pixels)| (*client, pixels.load(Ordering::Relaxed)))
+            .collect();
+        leaderboard.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        leaderboard.truncate(STATS_LEADERBOARD_SIZE);
+        let leaderboard = leaderboard
+            .into_iter()
+            .map(|(client, pixels)| LeaderboardEntry {
+                client: client_id(client),
+                pixels,
+            })
+            .collect();
+        Stats {
+            global,
+            canvases,
+            leaderboard,
+        }
+    }
+
+    /// Serialize the topics in `topics` to json
+    pub fn to_json(&self, topics: &[Topic]) -> String {
+        let message = StatsMessage {
+            global: topics.contains(&Topic::Global).then_some(&self.global),
+            canvases: topics
+                .contains(&Topic::Canvas)
+                .then_some(self.canvases.as_slice()),
+            leaderboard: topics
+                .contains(&Topic::Leaderboard)
+                .then_some(self.leaderboard.as_slice()),
+        };
+        serde_json::to_string(&message).expect("Stats are always serializable")
+    }
+}
+
+/// Collect the stats every [`STATS_INTERVAL`] and publish them to every subscriber
+pub(crate) async fn broadcast_stats(
+    grids: Arc<[Flut<u32>]>,
+    sender: watch::Sender<Arc<Stats>>,
+) -> AsyncResult<Never> {
+    let mut interval = interval(STATS_INTERVAL);
+    // the pixel counts of every canvas over the last second, the pixel rates are derived from it
+    let window = (Duration::from_secs(1).as_millis() / STATS_INTERVAL.as_millis()).max(1) as usize;
+    let mut counts: VecDeque<Vec<u64>> = VecDeque::with_capacity(window + 1);
+    loop {
+        interval.tick().await;
+        let mut stats = Stats::collect(&grids);
+        counts.push_back(stats.canvases.iter().map(|canvas| canvas.pixels).collect());
+        if counts.len() > window + 1 {
+            counts.pop_front();
+        }
+        let intervals = counts.len() as u64 - 1;
+        for (canvas, oldest) in stats.canvases.iter_mut().zip(&counts[0]) {
+            canvas.pixel_rate = (canvas.pixels.saturating_sub(*oldest) * window as u64)
+                .checked_div(intervals)
+                .unwrap_or(0);
+        }
+        sender.send_replace(Arc::new(stats));
+    }
+}
+
+/// What happened during `period` seconds starting at `time`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct HistorySample {
+    /// unix timestamp of the start of the sample
+    pub time: i64,
+    pub period: u64,
+    pub pixels: u64,
+    pub bytes: u64,
+    pub parse_errors: u64,
+    /// the most clients connected at once
+    pub clients: u64,
+}
+
+impl HistorySample {
+    /// Merge consecutive samples into one that covers all of them
+    fn downsample(samples: &[HistorySample]) -> HistorySample {
+        HistorySample {
+            time: samples
+                .first()
+                .map(|sample| sample.time)
+                .unwrap_or_default(),
+            period: samples.iter().map(|sample| sample.period).sum(),
+            pixels: samples.iter().map(|sample| sample.pixels).sum(),
+            bytes: samples.iter().map(|sample| sample.bytes).sum(),
+            parse_errors: samples.iter().map(|sample| sample.parse_errors).sum(),
+            clients: samples
+                .iter()
+                .map(|sample| sample.clients)
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Ring buffers of the last [`HISTORY_SECONDS`] seconds and the last [`HISTORY_MINUTES`]
+/// minutes, oldest first
+#[derive(Debug, Default, Serialize)]
+pub struct StatsHistory {
+    pub seconds: VecDeque<HistorySample>,
+    pub minutes: VecDeque<HistorySample>,
+    /// the seconds of the minute that is not finished yet
+    #[serde(skip)]
+    current_minute: Vec<HistorySample>,
+}
+
+impl StatsHistory {
+    pub fn record(&mut self, sample: HistorySample) {
+        if self.seconds.len() == HISTORY_SECONDS {
+            self.seconds.pop_front();
+        }
+        self.seconds.push_back(sample);
+        self.current_minute.push(sample);
+        if self.current_minute.len() == 60 {
+            if self.minutes.len() == HISTORY_MINUTES {
+                self.minutes.pop_front();
+            }
+            self.minutes
+                .push_back(HistorySample::downsample(&self.current_minute));
+            self.current_minute.clear();
+        }
+    }
+}
+
+/// Add a sample of the global counters to `history` every second
+pub(crate) async fn record_history(history: Arc<Mutex<StatsHistory>>) -> AsyncResult<Never> {
+    let totals = || {
+        [
+            COUNTER.load(Ordering::Relaxed),
+            BYTES.load(Ordering::Relaxed),
+            PARSE_ERRORS.load(Ordering::Relaxed),
+        ]
+    };
+    let mut interval = interval(Duration::from_secs(1));
+    interval.tick().await;
+    let mut last = totals();
+    loop {
+        interval.tick().await;
+        let now = totals();
+        let [pixels, bytes, parse_errors] = [0, 1, 2].map(|idx| now[idx].saturating_sub(last[idx]));
+        last = now;
+        let sample = HistorySample {
+            time: chrono::Utc::now().timestamp() - 1,
+            period: 1,
+            pixels,
+            bytes,
+            parse_errors,
+            clients: CLIENTS.load(Ordering::Relaxed),
+        };
+        history
+            .lock()
+            .expect("Could not lock stats history")
+            .record(sample);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_topics_are_filtered() {
+        let stats = Stats {
+            global: GlobalStats {
+                clients: 1,
+                pixels: 2,
+                encode_micros: 3,
+                runtime_lag_micros: 4,
+                rejected: 6,
+                recording_bytes: 8,
+            },
+            canvases: vec![CanvasStats {
+                canvas: 0,
+                width: 8,
+                height: 6,
+                pixels: 5,
+                pixel_rate: 7,
+            }],
+            leaderboard: vec![],
+        };
+        assert_eq!(
+            stats.to_json(&[Topic::Global]),
+            r#"{"global":{"clients":1,"pixels":2,"encode_micros":3,"runtime_lag_micros":4,"rejected":6,"recording_bytes":8}}"#
+        );
+        assert_eq!(
+            stats.to_json(&[Topic::Canvas, Topic::Leaderboard]),
+            r#"{"canvases":[{"canvas":0,"width":8,"height":6,"pixels":5,"pixel_rate":7}],"leaderboard":[]}"#
+        );
+        assert_eq!(stats.to_json(&[]), "{}");
+    }
+
+    #[test]
+    fn test_leaderboard_is_sorted() {
+        let low = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let high = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        client_counter(low).fetch_add(10, Ordering::Relaxed);
+        client_counter(high).fetch_add(20, Ordering::Relaxed);
+        let leaderboard = Stats::collect(&[]).leaderboard;
+        let position = |ip| {
+            leaderboard
+                .iter()
+                .position(|entry| entry.client == client_id(ip))
+        };
+        assert!(position(high).unwrap() < position(low).unwrap());
+        assert_eq!(client_id(low), client_id(low));
+        assert!(!leaderboard.iter().any(|entry| entry.client.contains("192")));
+    }
+
+    #[test]
+    fn test_client_counters_are_capped() {
+        let connected = client_counter(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)));
+        for idx in 0..STATS_TRACKED_CLIENTS as u32 {
+            client_counter(IpAddr::V6((idx as u128).into()));
+        }
+        let counters = CLIENT_COUNTERS.lock().unwrap();
+        assert!(counters.len() <= STATS_TRACKED_CLIENTS);
+        assert!(counters
+            .values()
+            .any(|pixels| Arc::ptr_eq(pixels, &connected)));
+    }
+
+    #[test]
+    fn test_history_downsamples_minutes() {
+        let mut history = StatsHistory::default();
+        for second in 0..(HISTORY_SECONDS + 60) {
+            history.record(HistorySample {
+                time: second as i64,
+                period: 1,
+                pixels: 2,
+                bytes: 3,
+                parse_errors: 0,
+                clients: second as u64 % 7,
+            });
+        }
+        assert_eq!(history.seconds.len(), HISTORY_SECONDS);
+        assert_eq!(history.seconds[0].time, 60);
+        assert_eq!(history.minutes.len(), HISTORY_SECONDS / 60 + 1);
+        assert_eq!(
+            history.minutes[1],
+            HistorySample {
+                time: 60,
+                period: 60,
+                pixels: 120,
+                bytes: 180,
+                parse_errors: 0,
+                clients: 6,
+            }
+        );
+    }
+}
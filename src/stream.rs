@@ -1,5 +1,6 @@
 use axum::http::{self, HeaderMap, HeaderValue};
 use axum_streams::StreamingFormat;
+use bytes::Bytes;
 use futures::StreamExt;
 use rand::{distr::StandardUniform, rng, Rng};
 
@@ -29,67 +30,47 @@ impl Multipart {
 }
 
 impl Multipart {
-    fn write_multipart_frame<T>(
-        obj: T,
-        boundary: Vec<u8>,
-        headers: HeaderMap,
-        first: bool,
-    ) -> Result<Vec<u8>, axum::Error>
-    where
-        T: IntoIterator<Item = u8>,
-    {
+    /// The boundary and headers that go in front of every part, the part itself is sent as its
+    /// own chunk so a shared frame is never copied per viewer
+    fn write_multipart_header(boundary: &[u8], headers: &HeaderMap, first: bool) -> Bytes {
         let mut frame_vec = Vec::new();
         if first {
             frame_vec.extend_from_slice(b"--");
         } else {
             frame_vec.extend_from_slice(b"\r\n--");
         }
-        frame_vec.extend(boundary);
+        frame_vec.extend_from_slice(boundary);
         frame_vec.extend_from_slice(b"\r\n");
         for (header_name, header_value) in headers {
-            match header_name {
-                Some(header) => {
-                    frame_vec.extend_from_slice(header.as_str().as_bytes());
-                    frame_vec.extend_from_slice(b": ");
-                    frame_vec.extend_from_slice(header_value.as_bytes());
-                    frame_vec.extend_from_slice(b"\r\n");
-                }
-                None => todo!(),
-            }
+            frame_vec.extend_from_slice(header_name.as_str().as_bytes());
+            frame_vec.extend_from_slice(b": ");
+            frame_vec.extend_from_slice(header_value.as_bytes());
+            frame_vec.extend_from_slice(b"\r\n");
         }
         frame_vec.extend_from_slice(b"\r\n");
-        frame_vec.extend(obj);
 
-        Ok(frame_vec)
+        frame_vec.into()
     }
 }
 
 impl<T> StreamingFormat<T> for Multipart
 where
-    T: Send + Sync + IntoIterator<Item = u8> + 'static,
+    T: Send + Sync + Into<Bytes> + 'static,
 {
     fn to_bytes_stream<'a, 'b>(
         &'a self,
         stream: futures::stream::BoxStream<'b, Result<T, axum::Error>>,
         _options: &'a axum_streams::StreamBodyAsOptions,
     ) -> futures::stream::BoxStream<'b, Result<axum::body::Bytes, axum::Error>> {
-        let boundary = self.boundary.clone();
-        let headers = self.headers.clone();
-        let first = self.first;
+        let header = Multipart::write_multipart_header(&self.boundary, &self.headers, self.first);
 
         Box::pin({
-            stream.map(move |obj_res| match obj_res {
-                Err(e) => Err(e),
-                Ok(obj) => {
-                    let picture_framed = Multipart::write_multipart_frame(
-                        obj,
-                        boundary.clone(),
-                        headers.clone(),
-                        first,
-                    );
-
-                    picture_framed.map(axum::body::Bytes::from)
-                }
+            stream.flat_map(move |obj_res| {
+                let parts = match obj_res {
+                    Err(e) => vec![Err(e)],
+                    Ok(obj) => vec![Ok(header.clone()), Ok(obj.into())],
+                };
+                futures::stream::iter(parts)
             })
         })
     }
@@ -0,0 +1,247 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{
+        mpsc::{sync_channel, Receiver, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use image::imageops::{self, FilterType};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    grid::{Flut, Frame},
+    Canvas,
+};
+
+/// How many frames can wait for a slow consumer before new ones are dropped
+const FRAME_QUEUE_LENGTH: usize = 2;
+/// How often dropped frames are reported
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The layout of the bytes of every pixel, named like ffmpeg names them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// red, green and blue, one byte each
+    Rgb24,
+    /// red, green, blue and alpha, one byte each
+    Rgba,
+}
+
+impl PixelFormat {
+    /// The name of the format for `-pixel_format` of ffmpeg
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::Rgb24 => "rgb24",
+            PixelFormat::Rgba => "rgba",
+        }
+    }
+}
+
+/// Where the frames of a [`VideoSink`] go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkTarget {
+    /// A file or a named pipe, pipes are opened once a reader opens them too
+    Path(PathBuf),
+    /// The stdin of a program started with these arguments, like
+    /// `["ffmpeg", "-f", "rawvideo", ...]`
+    Command(Vec<String>),
+}
+
+/// Writes raw frames of a canvas at a fixed frame rate, for external encoders like ffmpeg.
+/// The size of the video is the size the canvas is encoded at when the sink starts, later
+/// frames are scaled to it. Frames a slow consumer can't keep up with are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoSink {
+    pub canvas: Canvas,
+    pub fps: u32,
+    pub format: PixelFormat,
+    pub target: SinkTarget,
+}
+
+impl VideoSink {
+    /// The raw bytes of `frame`, scaled to `width` by `height` if it isn't that size
+    fn frame_bytes(&self, frame: &Frame, width: u32, height: u32) -> Vec<u8> {
+        match self.format {
+            PixelFormat::Rgb24 => {
+                let image = frame.to_rgb_image();
+                if image.dimensions() == (width, height) {
+                    return image.into_raw();
+                }
+                imageops::resize(&image, width, height, FilterType::Nearest).into_raw()
+            }
+            PixelFormat::Rgba => {
+                let image = frame.to_rgba_image();
+                if image.dimensions() == (width, height) {
+                    return image.into_raw();
+                }
+                imageops::resize(&image, width, height, FilterType::Nearest).into_raw()
+            }
+        }
+    }
+}
+
+/// Start a thread that takes the frames of every sink and one that writes them, the frame
+/// threads stop once `shutdown` is cancelled and their writers once the queue runs dry. Every
+/// sink logs the ffmpeg input options that read it.
+///
+/// # Errors
+///
+/// This function will return an error if a thread can't be spawned
+pub(crate) fn spawn_video_sinks(
+    grids: Arc<[Flut<u32>]>,
+    sinks: &[VideoSink],
+    shutdown: CancellationToken,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    let mut handles = Vec::new();
+    for (idx, sink) in sinks.iter().cloned().enumerate() {
+        let mut frame = Frame::new();
+        grids[sink.canvas as usize].snapshot(&mut frame);
+        let (size_x, size_y) = frame.get_size();
+        let (width, height) = (
+            (size_x * frame.scale()) as u32,
+            (size_y * frame.scale()) as u32,
+        );
+        let input = match &sink.target {
+            SinkTarget::Path(path) => path.display().to_string(),
+            SinkTarget::Command(_) => "-".to_string(),
+        };
+        tracing::info!(
+            "Video sink {idx} for canvas {}: {width}x{height} {} at {} fps to {:?}, read it with \
+             ffmpeg -f rawvideo -pixel_format {} -video_size {width}x{height} -framerate {} -i {input}",
+            sink.canvas,
+            sink.format.name(),
+            sink.fps,
+            sink.target,
+            sink.format.name(),
+            sink.fps,
+        );
+
+        let (sender, receiver) = sync_channel(FRAME_QUEUE_LENGTH);
+        let target = sink.target.clone();
+        thread::Builder::new()
+            .name(format!("flurry-video-writer-{idx}"))
+            .spawn(move || {
+                if let Err(err) = write_frames(target, receiver) {
+                    tracing::error!("Video sink {idx} stopped: {err:?}");
+                }
+            })?;
+        let grids = grids.clone();
+        let shutdown = shutdown.clone();
+        let interval = Duration::from_secs(1) / sink.fps.max(1);
+        let handle = thread::Builder::new()
+            .name(format!("flurry-video-{idx}"))
+            .spawn(move || {
+                let mut dropped = 0;
+                let mut reported = Instant::now();
+                let mut next = Instant::now();
+                while !shutdown.is_cancelled() {
+                    grids[sink.canvas as usize].snapshot(&mut frame);
+                    let bytes = sink.frame_bytes(&frame, width, height);
+                    match sender.try_send(bytes) {
+                        Ok(()) => (),
+                        Err(TrySendError::Full(_)) => dropped += 1,
+                        Err(TrySendError::Disconnected(_)) => break,
+                    }
+                    if dropped != 0 && reported.elapsed() >= DROP_REPORT_INTERVAL {
+                        tracing::warn!(
+                            "Video sink {idx} dropped {dropped} frames, the consumer is too slow"
+                        );
+                        dropped = 0;
+                        reported = Instant::now();
+                    }
+                    // keep the frame rate steady, a late frame doesn't delay the ones after it
+                    next += interval;
+                    let now = Instant::now();
+                    if next < now {
+                        next = now;
+                    }
+                    thread::sleep(next - now);
+                }
+            })?;
+        handles.push(handle);
+    }
+    Ok(handles)
+}
+
+/// Write every frame `receiver` gets to `target` until the sender is gone
+fn write_frames(target: SinkTarget, receiver: Receiver<Vec<u8>>) -> io::Result<()> {
+    let (mut output, mut child): (Box<dyn Write>, Option<Child>) = match target {
+        SinkTarget::Path(path) => (
+            Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?,
+            ),
+            None,
+        ),
+        SinkTarget::Command(args) => {
+            let (program, args) = args
+                .split_first()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+            let mut child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .spawn()?;
+            let stdin = child.stdin.take().expect("stdin is piped");
+            (Box::new(stdin), Some(child))
+        }
+    };
+    while let Ok(bytes) = receiver.recv() {
+        output.write_all(&bytes)?;
+    }
+    output.flush()?;
+    // closing stdin lets the program finish the video
+    drop(output);
+    if let Some(child) = &mut child {
+        child.wait()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    #[test]
+    fn test_frames_reach_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.raw");
+        let grids: Arc<[Flut<u32>]> = [Flut::init(2, 1, 0).with_scale(2)].into();
+        grids[0].set(1, 0, 0x12_34_56_78);
+        let sink = VideoSink {
+            canvas: 0,
+            fps: 100,
+            format: PixelFormat::Rgba,
+            target: SinkTarget::Path(path.clone()),
+        };
+        let shutdown = CancellationToken::new();
+        let handles = spawn_video_sinks(grids.clone(), &[sink], shutdown.clone()).unwrap();
+
+        // a 4x2 frame of 4 bytes per pixel
+        let frame_length = 4 * 2 * 4;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read(&path).unwrap_or_default().len() < 2 * frame_length {
+            assert!(Instant::now() < deadline, "no frames were written");
+            thread::sleep(Duration::from_millis(1));
+        }
+        shutdown.cancel();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let video = std::fs::read(&path).unwrap();
+        assert_eq!(
+            video[..frame_length / 2],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78, 0x12, 0x34, 0x56, 0x78]
+        );
+    }
+}
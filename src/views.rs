@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage};
+use tokio::{
+    sync::watch,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    config::MAX_VIEWS,
+    grid::{Flut, Frame},
+    wall::Wall,
+    Canvas,
+};
+
+/// The most a view may zoom in on a canvas
+pub(crate) const MAX_ZOOM: usize = 16;
+/// The longest side of the image a view encodes, in image pixels
+pub(crate) const MAX_VIEW_SIDE: usize = 8192;
+/// The longest side of a thumbnail, in image pixels
+const THUMBNAIL_SIDE: u32 = 256;
+/// Views start and end on multiples of this, so views that differ by a few pixels share an
+/// encoder
+pub(crate) const VIEW_GRID: usize = 16;
+
+/// A rectangle of a canvas or wall that is streamed on its own, like the quadrant of one team
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct View {
+    pub(crate) canvas: Canvas,
+    pub(crate) x: usize,
+    pub(crate) y: usize,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) zoom: usize,
+    /// shrink the view to fit [`THUMBNAIL_SIDE`], for galleries of many canvases
+    pub(crate) thumbnail: bool,
+}
+
+/// The jpegs of a canvas or a wall, walls are numbered after the canvases. `None` if neither
+/// exists.
+pub(crate) fn subscribe_jpg(
+    grids: &[Flut<u32>],
+    walls: &[Wall],
+    canvas: Canvas,
+) -> Option<watch::Receiver<Bytes>> {
+    match (canvas as usize).checked_sub(grids.len()) {
+        None => Some(grids[canvas as usize].subscribe_jpg()),
+        Some(wall) => Some(walls.get(wall)?.subscribe_jpg()),
+    }
+}
+
+/// The size of a canvas or a wall and how big its pixels are encoded
+pub(crate) fn canvas_size(
+    grids: &[Flut<u32>],
+    walls: &[Wall],
+    canvas: Canvas,
+) -> Option<(usize, usize, usize)> {
+    match (canvas as usize).checked_sub(grids.len()) {
+        None => {
+            let grid = &grids[canvas as usize];
+            let (size_x, size_y) = grid.get_size();
+            Some((size_x, size_y, grid.scale()))
+        }
+        Some(wall) => {
+            let (size_x, size_y) = walls.get(wall)?.get_size();
+            Some((size_x, size_y, 1))
+        }
+    }
+}
+
+/// Cut `view` out of the snapshot the latest jpeg of its canvas was encoded from
+fn encode_view(grids: &[Flut<u32>], walls: &[Wall], view: View) -> Option<Bytes> {
+    let crop = |frame: &Frame| frame.crop(view.x, view.y, view.width, view.height, view.zoom);
+    let frame = match (view.canvas as usize).checked_sub(grids.len()) {
+        None => grids[view.canvas as usize].with_frame(crop),
+        Some(wall) => walls[wall].with_frame(crop),
+    };
+    if frame.pixels().is_empty() {
+        return None;
+    }
+    let mut jpgbuf = Vec::new();
+    let encoded = match view.thumbnail {
+        true => DynamicImage::ImageRgb8(frame.to_rgb_image())
+            .thumbnail(THUMBNAIL_SIDE, THUMBNAIL_SIDE)
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpgbuf, 50)),
+        false => frame.encode_jpg(50, &mut jpgbuf),
+    };
+    if let Err(err) = encoded {
+        tracing::error!("Error writing jpeg buffer: {:?}", err);
+        return None;
+    }
+    Some(jpgbuf.into())
+}
+
+/// The jpegs of every view someone is watching. Each view is encoded once, however many
+/// viewers share it, and stops being encoded once the last one leaves.
+#[derive(Default)]
+pub(crate) struct Views {
+    streams: Mutex<HashMap<View, watch::Sender<Bytes>>>,
+}
+
+impl Views {
+    fn lock(&self) -> MutexGuard<'_, HashMap<View, watch::Sender<Bytes>>> {
+        self.streams.lock().expect("Could not lock views")
+    }
+
+    /// Get the jpegs of `view`, starting its encoder if nobody watches it yet. The view is
+    /// encoded at most once per `interval`, the rate its viewers take frames at. `view` has to be
+    /// on an existing canvas or wall. `None` if [`MAX_VIEWS`] other views are encoded already.
+    pub(crate) fn subscribe(
+        self: &Arc<Self>,
+        grids: Arc<[Flut<u32>]>,
+        walls: Arc<[Wall]>,
+        view: View,
+        interval: Duration,
+    ) -> Option<watch::Receiver<Bytes>> {
+        let mut streams = self.lock();
+        if let Some(sender) = streams.get(&view) {
+            return Some(sender.subscribe());
+        }
+        if streams.len() >= MAX_VIEWS {
+            return None;
+        }
+        let sender = watch::Sender::new(Bytes::new());
+        let receiver = sender.subscribe();
+        streams.insert(view, sender.clone());
+        let updates = subscribe_jpg(&grids, &walls, view.canvas).expect("view is on a canvas");
+        tokio::spawn(
+            self.clone()
+                .encode(grids, walls, view, interval, sender, updates),
+        );
+        Some(receiver)
+    }
+
+    /// Encode `view` whenever its canvas gets a new jpeg but at most once per `interval`, until
+    /// nobody watches it anymore
+    async fn encode(
+        self: Arc<Self>,
+        grids: Arc<[Flut<u32>]>,
+        walls: Arc<[Wall]>,
+        view: View,
+        interval: Duration,
+        sender: watch::Sender<Bytes>,
+        mut updates: watch::Receiver<Bytes>,
+    ) {
+        updates.mark_changed();
+        let mut next = Instant::now();
+        loop {
+            let update = async {
+                sleep_until(next).await;
+                updates.changed().await
+            };
+            tokio::select! {
+                changed = update => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    next = Instant::now() + interval;
+                    let (grids, walls) = (grids.clone(), walls.clone());
+                    let jpg = tokio::task::spawn_blocking(move || encode_view(&grids, &walls, view));
+                    match jpg.await {
+                        Ok(Some(jpg)) => {
+                            sender.send_replace(jpg);
+                        }
+                        Ok(None) => (),
+                        Err(_) => break,
+                    }
+                }
+                _ = sender.closed() => {
+                    // somebody may have subscribed since, they hold the lock while they do
+                    let mut streams = self.lock();
+                    if sender.receiver_count() == 0 {
+                        streams.remove(&view);
+                        return;
+                    }
+                }
+            }
+        }
+        self.lock().remove(&view);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use image::GenericImageView;
+
+    use super::*;
+    use crate::grid::Grid;
+
+    #[tokio::test]
+    async fn test_views_are_shared_and_cropped() {
+        let grids: Arc<[Flut<u32>]> = Arc::new([Flut::init(8, 8, 0)]);
+        grids[0].update_jpg_buffer();
+        let views = Arc::new(Views::default());
+        let view = View {
+            canvas: 0,
+            x: 4,
+            y: 0,
+            width: 4,
+            height: 2,
+            zoom: 3,
+            thumbnail: false,
+        };
+        let interval = Duration::from_secs(60);
+        let mut first = views
+            .subscribe(grids.clone(), Arc::new([]), view, interval)
+            .unwrap();
+        let second = views
+            .subscribe(grids.clone(), Arc::new([]), view, interval)
+            .unwrap();
+        assert_eq!(views.lock().len(), 1);
+
+        tokio::time::timeout(Duration::from_secs(5), first.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let jpg = first.borrow_and_update().clone();
+        let image = image::load_from_memory(&jpg).unwrap();
+        assert_eq!(image.dimensions(), (12, 6));
+
+        // the next update waits for the interval
+        grids[0].set(0, 0, 0xff_ff_ff_ff);
+        assert!(grids[0].update_jpg_buffer());
+        let changed = tokio::time::timeout(Duration::from_millis(100), first.changed()).await;
+        assert!(changed.is_err());
+
+        let thumbnail = View {
+            canvas: 0,
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 4,
+            zoom: 64,
+            thumbnail: true,
+        };
+        let jpg = encode_view(&grids, &[], thumbnail).unwrap();
+        let image = image::load_from_memory(&jpg).unwrap();
+        assert_eq!(image.dimensions(), (THUMBNAIL_SIDE, THUMBNAIL_SIDE / 2));
+
+        // past the limit only the views that are encoded already can be watched
+        let others: Vec<_> = (1..MAX_VIEWS)
+            .map(|idx| {
+                let view = View {
+                    zoom: MAX_ZOOM + idx,
+                    ..view
+                };
+                views.subscribe(grids.clone(), Arc::new([]), view, interval)
+            })
+            .collect();
+        assert!(others.iter().all(Option::is_some));
+        let full = View { zoom: 0, ..view };
+        assert!(views
+            .subscribe(grids.clone(), Arc::new([]), full, interval)
+            .is_none());
+        assert!(views
+            .subscribe(grids.clone(), Arc::new([]), view, interval)
+            .is_some());
+        drop(others);
+
+        drop(first);
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !views.lock().is_empty() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use bytes::Bytes;
+use tokio::sync::watch;
+
+use crate::{
+    clients::ClientId,
+    grid::{Flut, Frame},
+    Canvas, Coordinate,
+};
+
+/// What a wall shows where no tile covers it
+const GAP: u32 = 0x00_00_00_ff;
+
+/// A rectangle of a canvas placed on a [`Wall`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    /// The canvas the pixels of the tile are read from and written to
+    pub canvas: Canvas,
+    /// The top left corner of the tile on the wall
+    pub x: usize,
+    pub y: usize,
+    /// The top left corner of the rectangle of the canvas the tile shows
+    pub src_x: usize,
+    pub src_y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A virtual canvas made of tiles of real canvases, for setups with one canvas per screen or
+/// one canvas spread over several screens. Writes go to the canvas of the tile they land on and
+/// reads come from it, where tiles overlap the one added last wins.
+///
+/// Walls are addressed like canvases, they are numbered after the real ones.
+pub struct Wall {
+    size_x: usize,
+    size_y: usize,
+    tiles: Box<[Tile]>,
+    last_hash: AtomicU64,
+    /// the frame the latest jpeg was encoded from
+    frame: Mutex<Frame>,
+    /// the frame before it, snapshots are copied into it to reuse its allocation
+    spare: Mutex<Frame>,
+    jpg: watch::Sender<Bytes>,
+}
+
+impl Wall {
+    /// A wall just big enough for every tile
+    pub fn new(tiles: &[Tile]) -> Wall {
+        Wall {
+            size_x: tiles
+                .iter()
+                .map(|tile| tile.x.saturating_add(tile.width))
+                .max()
+                .unwrap_or(0),
+            size_y: tiles
+                .iter()
+                .map(|tile| tile.y.saturating_add(tile.height))
+                .max()
+                .unwrap_or(0),
+            tiles: tiles.into(),
+            last_hash: AtomicU64::new(0),
+            frame: Mutex::new(Frame::new()),
+            spare: Mutex::new(Frame::new()),
+            jpg: watch::Sender::new(Bytes::new()),
+        }
+    }
+
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.size_x, self.size_y)
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    /// The canvas and the position on it the pixel at `x`, `y` of the wall belongs to, `None` if
+    /// no tile covers it
+    pub fn locate(&self, x: Coordinate, y: Coordinate) -> Option<(Canvas, Coordinate, Coordinate)> {
+        let (x, y) = (x as usize, y as usize);
+        let tile = self.tiles.iter().rev().find(|tile| {
+            (tile.x..tile.x + tile.width).contains(&x)
+                && (tile.y..tile.y + tile.height).contains(&y)
+        })?;
+        Some((
+            tile.canvas,
+            Coordinate::try_from(tile.src_x + x - tile.x).ok()?,
+            Coordinate::try_from(tile.src_y + y - tile.y).ok()?,
+        ))
+    }
+
+    /// The pixel at `x`, `y` as the canvas of its tile gives it to clients, `None` if it is
+    /// outside the wall
+    pub fn read(&self, grids: &[Flut<u32>], x: Coordinate, y: Coordinate) -> Option<u32> {
+        if x as usize >= self.size_x || y as usize >= self.size_y {
+            return None;
+        }
+        Some(
+            self.locate(x, y)
+                .and_then(|(canvas, x, y)| grids.get(canvas as usize)?.read(x, y))
+                .unwrap_or(GAP),
+        )
+    }
+
+    /// Set the pixel at `x`, `y` on the canvas of its tile, see [`Flut::set_by`]. Returns the
+    /// canvas that was written to and whether the write went through, `None` if no tile covers
+    /// the pixel.
+    pub fn set_by(
+        &self,
+        grids: &[Flut<u32>],
+        x: Coordinate,
+        y: Coordinate,
+        value: u32,
+        client: ClientId,
+    ) -> Option<(Canvas, bool)> {
+        let (canvas, x, y) = self.locate(x, y)?;
+        let grid = grids.get(canvas as usize)?;
+        Some((canvas, grid.set_by(x, y, value, client)))
+    }
+
+    /// Compose the wall as it is shown into `frame`, every canvas is copied once
+    pub fn snapshot(&self, grids: &[Flut<u32>], frame: &mut Frame) {
+        let mut sources: Vec<Option<Frame>> = vec![None; grids.len()];
+        let mut pixels = vec![GAP; self.size_x * self.size_y];
+        for tile in self.tiles.iter() {
+            let Some(grid) = grids.get(tile.canvas as usize) else {
+                continue;
+            };
+            let source = sources[tile.canvas as usize].get_or_insert_with(|| {
+                let mut source = Frame::new();
+                grid.snapshot(&mut source);
+                source
+            });
+            let (source_x, source_y) = source.get_size();
+            let rows = tile.height.min(source_y.saturating_sub(tile.src_y));
+            let columns = tile.width.min(source_x.saturating_sub(tile.src_x));
+            for row in 0..rows {
+                let from = (tile.src_y + row) * source_x + tile.src_x;
+                let to = (tile.y + row) * self.size_x + tile.x;
+                pixels[to..to + columns].copy_from_slice(&source.pixels()[from..from + columns]);
+            }
+        }
+        *frame = Frame::from_pixels(self.size_x, self.size_y, pixels);
+    }
+
+    /// Run `f` on the composition the latest jpeg was encoded from
+    pub fn with_frame<R>(&self, f: impl FnOnce(&Frame) -> R) -> R {
+        f(&self.frame.lock().expect("Could not lock frame"))
+    }
+
+    /// Get notified of every new jpeg of the wall
+    pub fn subscribe_jpg(&self) -> watch::Receiver<Bytes> {
+        self.jpg.subscribe()
+    }
+
+    /// Encode a new jpeg if the wall changed, returns whether it did.
+    ///
+    /// This is CPU heavy, so it should not be called from the async runtime
+    pub fn update_jpg_buffer(&self, grids: &[Flut<u32>]) -> bool {
+        let mut spare = self.spare.lock().expect("Could not lock spare frame");
+        self.snapshot(grids, &mut spare);
+        let hash = spare.content_hash();
+        if self.last_hash.swap(hash, Ordering::Relaxed) == hash {
+            return false;
+        }
+        let mut jpgbuf = Vec::new();
+        if let Err(err) = spare.encode_jpg(50, &mut jpgbuf) {
+            tracing::error!("Error writing jpeg buffer: {:?}", err);
+            return false;
+        }
+        std::mem::swap(
+            &mut *self.frame.lock().expect("Could not lock frame"),
+            &mut spare,
+        );
+        self.jpg.send_replace(jpgbuf.into());
+        true
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_return)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    fn tile(canvas: Canvas, x: usize, src_x: usize, width: usize) -> Tile {
+        Tile {
+            canvas,
+            x,
+            y: 0,
+            src_x,
+            src_y: 0,
+            width,
+            height: 2,
+        }
+    }
+
+    #[test]
+    fn test_tiles_route_reads_and_writes() {
+        let grids = [Flut::init(2, 2, 0), Flut::init(2, 2, 0)];
+        let wall = Wall::new(&[tile(0, 0, 0, 2), tile(1, 3, 0, 2)]);
+        assert_eq!(wall.get_size(), (5, 2));
+        assert_eq!(wall.locate(4, 1), Some((1, 1, 1)));
+        assert_eq!(wall.locate(2, 0), None);
+
+        assert_eq!(wall.set_by(&grids, 3, 1, 0x12_34_56_ff, 0), Some((1, true)));
+        assert_eq!(grids[1].get(0, 1), Some(0x12_34_56_ff));
+        assert_eq!(wall.set_by(&grids, 2, 1, 1, 0), None);
+        assert_eq!(wall.read(&grids, 3, 1), Some(0x12_34_56_ff));
+        assert_eq!(wall.read(&grids, 2, 1), Some(GAP));
+        assert_eq!(wall.read(&grids, 5, 1), None);
+
+        let mut frame = Frame::new();
+        wall.snapshot(&grids, &mut frame);
+        assert_eq!(frame.get_size(), (5, 2));
+        assert_eq!(frame.pixels()[5..], [0, 0, GAP, 0x12_34_56_ff, 0]);
+    }
+
+    #[test]
+    fn test_viewports_of_one_canvas() {
+        let grids = [Flut::init(4, 2, 0)];
+        grids[0].set(3, 0, 7);
+        // show the right half of the canvas on the left and the left half on the right
+        let wall = Wall::new(&[tile(0, 0, 2, 2), tile(0, 2, 0, 2)]);
+        assert_eq!(wall.read(&grids, 1, 0), Some(7));
+        let mut frame = Frame::new();
+        wall.snapshot(&grids, &mut frame);
+        assert_eq!(frame.pixels()[..4], [0, 7, 0, 0]);
+
+        // a tile that reaches past its canvas keeps the gap color
+        grids[0]
+            .resize(3, 2, crate::grid::ResizeMode::Scale)
+            .unwrap();
+        wall.snapshot(&grids, &mut frame);
+        assert_eq!(frame.pixels()[1], GAP);
+    }
+}
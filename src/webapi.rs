@@ -1,27 +1,58 @@
-use std::{net::SocketAddr, process::exit, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
-    extract::{ws::Message, ConnectInfo, Query, State, WebSocketUpgrade},
-    http::{self, HeaderMap, HeaderValue},
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, DefaultBodyLimit, Path, Query, State, WebSocketUpgrade,
+    },
+    http::{self, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{delete, get, post, put},
+    Json, Router,
 };
 use axum_extra::TypedHeader;
 use axum_streams::StreamBodyAs;
-use futures::{never::Never, stream::repeat_with, Stream};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{never::Never, Stream};
+use headers::{authorization::Bearer, Authorization};
+use image::{codecs::png::PngEncoder, RgbImage};
 use rust_embed::RustEmbed;
-use serde::Deserialize;
-use tokio::{net::TcpListener, time::interval};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpListener,
+    sync::watch,
+    time::{interval, MissedTickBehavior},
+};
+use tokio_stream::wrappers::WatchStream;
+use tokio_util::io::ReaderStream;
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
 use crate::{
-    config::{WEB_HOST, WEB_UPDATE_INTERVAL},
-    grid,
+    archive,
+    clients::{client_info, ClientId, ClientInfo},
+    config::{STATS_INTERVAL, STATS_MAX_INTERVAL, THUMBNAIL_UPDATE_INTERVAL, WEB_UPDATE_INTERVAL},
+    grid::{self, Anchor, Frame, ResizeMode},
+    protection::ProtectedRegion,
+    recordings::Recordings,
+    server::archive_path,
+    stats::{Stats, StatsHistory, Topic},
     stream::Multipart,
-    AsyncResult, CLIENTS, COUNTER,
+    views::{canvas_size, subscribe_jpg, View, Views, MAX_VIEW_SIDE, MAX_ZOOM, VIEW_GRID},
+    wall::Wall,
+    Activity, AsyncResult, Coordinate,
 };
 
+/// The largest archive admins can import
+const MAX_ARCHIVE_UPLOAD: usize = 256 * 1024 * 1024;
+
 #[derive(RustEmbed, Clone)]
 #[folder = "assets/"]
 struct Assets;
@@ -29,9 +60,21 @@ struct Assets;
 #[derive(Clone)]
 pub struct WebApiContext {
     pub grids: Arc<[grid::Flut<u32>]>,
+    /// Streamed as the canvases after `grids`
+    pub walls: Arc<[Wall]>,
+    /// The cropped and zoomed streams viewers are watching
+    pub(crate) views: Arc<Views>,
+    pub stats: watch::Receiver<Arc<Stats>>,
+    pub history: Arc<Mutex<StatsHistory>>,
+    /// The recorded frames `/history` serves, `None` if the server doesn't record
+    pub(crate) recordings: Option<Arc<Recordings>>,
+    /// The directory `/archive` reads the lossless archives from, `None` if there are none
+    pub(crate) archives: Option<PathBuf>,
+    /// The bearer token admin requests have to carry, admin requests are refused without one
+    pub admin_token: Option<Arc<str>>,
 }
 
-pub async fn serve(ctx: WebApiContext) -> AsyncResult<Never> {
+pub async fn serve(ctx: WebApiContext, listener: TcpListener) -> AsyncResult<Never> {
     let assets = axum_embed::ServeEmbed::<Assets>::with_parameters(
         Some("404.html".to_string()),
         axum_embed::FallbackBehavior::NotFound,
@@ -40,6 +83,30 @@ pub async fn serve(ctx: WebApiContext) -> AsyncResult<Never> {
     let app = Router::new()
         .route("/imgstream", get(image_stream))
         .route("/stats", get(stats_stream))
+        .route("/stats/history", get(stats_history))
+        .route("/canvases", get(list_canvases))
+        .route("/history/{canvas}", get(history_index))
+        .route("/history/{canvas}/frame", get(history_frame))
+        .route("/archive/{canvas}", get(download_archive))
+        .route("/archive/{canvas}/frame.png", get(archive_frame))
+        .route("/canvas/{canvas}/image.png", get(png_snapshot))
+        .route("/canvas/{canvas}/raw", get(raw_snapshot))
+        .route("/canvas/{canvas}/heatmap.png", get(heatmap))
+        .route("/canvas/{canvas}/age.png", get(age_map))
+        .route("/canvas/{canvas}/activity/reset", post(reset_activity))
+        .route("/canvas/{canvas}/pixel/{x}/{y}", get(pixel_owner))
+        .route(
+            "/canvas/{canvas}/protected",
+            get(protected_regions).post(protect_rect),
+        )
+        .route("/canvas/{canvas}/protected/mask", put(protect_mask))
+        .route("/canvas/{canvas}/protected/{id}", delete(unprotect))
+        .route("/canvas/{canvas}/reset", post(reset_canvas))
+        .route("/canvas/{canvas}/resize", post(resize_canvas))
+        .route(
+            "/canvas/{canvas}/import",
+            post(import_archive).layer(DefaultBodyLimit::max(MAX_ARCHIVE_UPLOAD)),
+        )
         .fallback_service(assets)
         .with_state(ctx)
         // logging middleware
@@ -49,13 +116,6 @@ pub async fn serve(ctx: WebApiContext) -> AsyncResult<Never> {
         );
 
     // run it with hyper
-    let Ok(listener) = TcpListener::bind(WEB_HOST).await else {
-        tracing::error!(
-            "Was unable to bind to {WEB_HOST}, please check if a different process is bound"
-        );
-        exit(1);
-    };
-
     tracing::debug!("listening on {}", listener.local_addr()?);
 
     axum::serve(
@@ -67,49 +127,178 @@ pub async fn serve(ctx: WebApiContext) -> AsyncResult<Never> {
     Err("Web api exited".into())
 }
 
+/// Which canvas `/imgstream` streams, with an optional rectangle of it and a zoom factor
 #[derive(Debug, Deserialize)]
-struct CanvasQuery {
+struct StreamQuery {
     canvas: u8,
+    x: Option<usize>,
+    y: Option<usize>,
+    w: Option<usize>,
+    h: Option<usize>,
+    scale: Option<usize>,
+    thumbnail: Option<bool>,
+}
+
+impl StreamQuery {
+    /// The part of the canvas to stream, `None` for the whole canvas as it is encoded anyway.
+    /// Thumbnails are always a view of their own.
+    ///
+    /// # Errors
+    ///
+    /// `NOT_FOUND` if the canvas doesn't exist, `BAD_REQUEST` if the rectangle isn't on the
+    /// canvas or the zoomed image gets too big. The rectangle grows to the next multiples of
+    /// [`VIEW_GRID`] that are still on the canvas.
+    fn view(&self, ctx: &WebApiContext) -> Result<Option<View>, StatusCode> {
+        let (size_x, size_y, scale) =
+            canvas_size(&ctx.grids, &ctx.walls, self.canvas).ok_or(StatusCode::NOT_FOUND)?;
+        let thumbnail = self.thumbnail.unwrap_or(false);
+        if !thumbnail
+            && [self.x, self.y, self.w, self.h, self.scale]
+                .iter()
+                .all(Option::is_none)
+        {
+            return Ok(None);
+        }
+        let x = self.x.unwrap_or(0);
+        let y = self.y.unwrap_or(0);
+        let width = self.w.unwrap_or(size_x.saturating_sub(x));
+        let height = self.h.unwrap_or(size_y.saturating_sub(y));
+        let zoom = self.scale.unwrap_or(1);
+        let fits = |start: usize, len: usize, size: usize| {
+            len != 0 && start.checked_add(len).is_some_and(|end| end <= size)
+        };
+        if !(1..=MAX_ZOOM).contains(&zoom) || !fits(x, width, size_x) || !fits(y, height, size_y) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let snap = |start: usize, len: usize, size: usize| {
+            let snapped = start - start % VIEW_GRID;
+            let end = (start + len).next_multiple_of(VIEW_GRID).min(size);
+            (snapped, end - snapped)
+        };
+        let ((x, width), (y, height)) = (snap(x, width, size_x), snap(y, height, size_y));
+        if width.max(height).saturating_mul(zoom * scale) > MAX_VIEW_SIDE {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(Some(View {
+            canvas: self.canvas,
+            x,
+            y,
+            width,
+            height,
+            zoom,
+            thumbnail,
+        }))
+    }
 }
 
 fn make_image_stream(
-    ctx: WebApiContext,
-    canvas: u8,
-) -> impl Stream<Item = Result<Vec<u8>, axum::Error>> {
+    jpg: watch::Receiver<Bytes>,
+    interval: Duration,
+) -> impl Stream<Item = Result<Bytes, axum::Error>> {
     use tokio_stream::StreamExt;
-    let mut buf = Vec::new();
-    repeat_with(move || {
-        buf.clear();
-        buf.extend_from_slice(&ctx.grids[canvas as usize].read_jpg_buffer());
-        Ok(buf.clone())
-    })
-    .throttle(WEB_UPDATE_INTERVAL)
+    WatchStream::new(jpg)
+        .filter(|jpg| !jpg.is_empty())
+        .map(Ok)
+        .throttle(interval)
 }
 
-fn make_stats() -> Message {
-    let pixels: u64 = COUNTER.load(std::sync::atomic::Ordering::Relaxed);
-    let clients: u64 = CLIENTS.load(std::sync::atomic::Ordering::Relaxed);
-    format!("{{\"c\":{clients}, \"p\":{pixels}}}").into()
+/// Sent by a stats subscriber to change what it gets, fields that are left out keep their value
+#[derive(Debug, Deserialize)]
+struct Subscription {
+    topics: Option<Vec<Topic>>,
+    /// milliseconds between updates
+    interval: Option<u64>,
 }
 
-async fn stats_stream(ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(|mut c| async move {
-        let mut interval = interval(Duration::from_millis(100));
-        loop {
-            interval.tick().await;
-            if let Err(e) = c.send(make_stats()).await {
-                tracing::warn!("websocket disconnected with {e:?}")
+async fn stats_stream(ws: WebSocketUpgrade, State(ctx): State<WebApiContext>) -> Response {
+    ws.on_upgrade(|socket| send_stats(socket, ctx.stats))
+}
+
+/// Send the subscribed topics until the subscriber disconnects, subscribers start out with the
+/// global stats every [`STATS_INTERVAL`]
+async fn send_stats(mut socket: WebSocket, stats: watch::Receiver<Arc<Stats>>) {
+    let mut topics = vec![Topic::Global];
+    let mut ticker = interval(STATS_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let message = stats.borrow().to_json(&topics);
+                if let Err(err) = socket.send(message.into()).await {
+                    tracing::debug!("stats subscriber disconnected with {err:?}");
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<Subscription>(text.as_str()) {
+                        Ok(subscription) => {
+                            if let Some(new_topics) = subscription.topics {
+                                topics = new_topics;
+                            }
+                            if let Some(millis) = subscription.interval {
+                                let period = Duration::from_millis(millis)
+                                    .clamp(STATS_INTERVAL, STATS_MAX_INTERVAL);
+                                ticker = interval(period);
+                                ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                            }
+                        }
+                        Err(err) => tracing::debug!("Invalid stats subscription: {err}"),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => return,
+                Some(Ok(_)) => (),
+                Some(Err(err)) => {
+                    tracing::debug!("stats subscriber disconnected with {err:?}");
+                    return;
+                }
             }
         }
-    })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryRange {
+    Seconds,
+    Minutes,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsHistoryQuery {
+    /// only serve the samples of this series, both if left out
+    range: Option<HistoryRange>,
+}
+
+async fn stats_history(
+    State(ctx): State<WebApiContext>,
+    Query(query): Query<StatsHistoryQuery>,
+) -> Response {
+    let history = ctx.history.lock().expect("Could not lock stats history");
+    match query.range {
+        Some(HistoryRange::Seconds) => Json(&history.seconds).into_response(),
+        Some(HistoryRange::Minutes) => Json(&history.minutes).into_response(),
+        None => Json(&*history).into_response(),
+    }
 }
 
 async fn image_stream(
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(ctx): State<WebApiContext>,
-    Query(CanvasQuery { canvas }): Query<CanvasQuery>,
-) -> impl IntoResponse {
+    Query(query): Query<StreamQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let interval = match query.thumbnail {
+        Some(true) => THUMBNAIL_UPDATE_INTERVAL,
+        _ => WEB_UPDATE_INTERVAL,
+    };
+    let jpg = match query.view(&ctx)? {
+        Some(view) => ctx
+            .views
+            .subscribe(ctx.grids.clone(), ctx.walls.clone(), view, interval)
+            .ok_or(StatusCode::SERVICE_UNAVAILABLE)?,
+        None => subscribe_jpg(&ctx.grids, &ctx.walls, query.canvas).ok_or(StatusCode::NOT_FOUND)?,
+    };
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
@@ -122,5 +311,562 @@ async fn image_stream(
         HeaderValue::from_static("image/jpeg"),
     );
 
-    StreamBodyAs::new(Multipart::new(10, headers), make_image_stream(ctx, canvas))
+    Ok(StreamBodyAs::new(
+        Multipart::new(10, headers),
+        make_image_stream(jpg, interval),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct CanvasEntry {
+    id: u8,
+    name: String,
+    /// `canvas` or `wall`
+    kind: &'static str,
+    width: usize,
+    height: usize,
+    /// how many image pixels wide and high every pixel is in the stream
+    scale: usize,
+    /// only counted for canvases, walls write to them
+    pixels: Option<u64>,
+    pixel_rate: Option<u64>,
+}
+
+/// Every canvas and wall with what a gallery shows about them, in the order they are numbered
+async fn list_canvases(State(ctx): State<WebApiContext>) -> Json<Vec<CanvasEntry>> {
+    let stats = ctx.stats.borrow().clone();
+    let canvases = ctx.grids.iter().enumerate().map(|(id, grid)| {
+        let (width, height) = grid.get_size();
+        let stats = stats.canvases.get(id);
+        CanvasEntry {
+            id: id as u8,
+            name: grid
+                .name()
+                .map_or_else(|| format!("canvas {id}"), str::to_string),
+            kind: "canvas",
+            width,
+            height,
+            scale: grid.scale(),
+            pixels: stats.map(|stats| stats.pixels),
+            pixel_rate: stats.map(|stats| stats.pixel_rate),
+        }
+    });
+    let walls = ctx.walls.iter().enumerate().map(|(wall, tiles)| {
+        let (width, height) = tiles.get_size();
+        CanvasEntry {
+            id: (ctx.grids.len() + wall) as u8,
+            name: format!("wall {wall}"),
+            kind: "wall",
+            width,
+            height,
+            scale: 1,
+            pixels: None,
+            pixel_rate: None,
+        }
+    });
+    Json(canvases.chain(walls).collect())
+}
+
+/// When every recorded frame of a canvas was taken, as unix timestamps in milliseconds oldest
+/// first, 404 if the server doesn't record or the canvas doesn't exist
+async fn history_index(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<Json<Vec<i64>>, StatusCode> {
+    let recordings = ctx.recordings.ok_or(StatusCode::NOT_FOUND)?;
+    recordings
+        .times(canvas)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// unix timestamp in milliseconds
+    time: i64,
+}
+
+/// The recorded frame of a canvas closest to `time`, when it was taken is in the `x-frame-time`
+/// header
+async fn history_frame(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let recordings = ctx.recordings.ok_or(StatusCode::NOT_FOUND)?;
+    let (time, path) = recordings
+        .nearest(canvas, query.time)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let jpg = match tokio::fs::read(path).await {
+        Ok(jpg) => jpg,
+        // queued frames may not be on disk yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(StatusCode::NOT_FOUND)
+        }
+        Err(err) => {
+            tracing::error!("Error reading recorded frame: {err:?}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, "image/jpeg".to_string()),
+            (
+                http::HeaderName::from_static("x-frame-time"),
+                time.to_string(),
+            ),
+        ],
+        jpg,
+    ))
+}
+
+/// The whole lossless archive of a canvas, 404 if the server doesn't keep archives
+async fn download_archive(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let dir = ctx.archives.ok_or(StatusCode::NOT_FOUND)?;
+    let file = tokio::fs::File::open(archive_path(&dir, canvas))
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    // the archive keeps growing while it is sent, stop at the end of the last whole record
+    let len = file
+        .metadata()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+    Ok((
+        [
+            (
+                http::header::CONTENT_TYPE,
+                "application/octet-stream".to_string(),
+            ),
+            (http::header::CONTENT_LENGTH, len.to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(file.take(len))),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct ArchiveQuery {
+    /// unix timestamp in milliseconds, the latest frame if left out
+    time: Option<i64>,
+}
+
+/// Open an archive with `open` and read its frame as it was at `time`, both off the async
+/// runtime. 400 if it isn't an archive and 404 if it is empty.
+async fn read_archive<R, F>(open: F, time: Option<i64>) -> Result<(i64, Frame), StatusCode>
+where
+    R: std::io::Read + std::io::Seek,
+    F: FnOnce() -> Result<R, StatusCode> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut archive = open()?;
+        archive::read_frame(&mut archive, time.unwrap_or(i64::MAX)).map_err(|err| {
+            tracing::warn!("Could not read archive: {err:?}");
+            StatusCode::BAD_REQUEST
+        })
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// A frame of the lossless archive of a canvas as a png, when it was taken is in the
+/// `x-frame-time` header
+async fn archive_frame(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let dir = ctx.archives.ok_or(StatusCode::NOT_FOUND)?;
+    let path = archive_path(&dir, canvas);
+    let open = move || std::fs::File::open(path).map_err(|_| StatusCode::NOT_FOUND);
+    let (time, frame) = read_archive(open, query.time).await?;
+    let png = tokio::task::spawn_blocking(move || {
+        let mut buf = Vec::new();
+        frame.encode_png(&mut buf).map(|_| buf)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, "image/png".to_string()),
+            (
+                http::HeaderName::from_static("x-frame-time"),
+                time.to_string(),
+            ),
+        ],
+        png,
+    ))
+}
+
+/// Seed a canvas with the frame of the uploaded archive as it was at `time`, resizing the
+/// canvas to it
+async fn import_archive(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    Query(query): Query<ArchiveQuery>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    body: Bytes,
+) -> StatusCode {
+    if let Err(status) = check_admin(&ctx, auth) {
+        return status;
+    }
+    if ctx.grids.get(canvas as usize).is_none() {
+        return StatusCode::NOT_FOUND;
+    }
+    let frame = match read_archive(move || Ok(std::io::Cursor::new(body)), query.time).await {
+        Ok((_, frame)) => frame,
+        Err(status) => return status,
+    };
+    let restore = tokio::task::spawn_blocking(move || ctx.grids[canvas as usize].restore(&frame));
+    match restore.await {
+        Ok(Ok(())) => StatusCode::NO_CONTENT,
+        Ok(Err(_)) => StatusCode::BAD_REQUEST,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Take a snapshot of `canvas` off the async runtime and turn it into a response body
+async fn with_snapshot<F, R>(ctx: WebApiContext, canvas: u8, f: F) -> Result<R, StatusCode>
+where
+    F: FnOnce(&Frame) -> image::ImageResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    with_canvas(ctx, canvas, |grid| {
+        let mut frame = Frame::new();
+        grid.snapshot(&mut frame);
+        f(&frame)
+    })
+    .await
+}
+
+/// Run `f` on `canvas` off the async runtime and turn its result into a response body
+async fn with_canvas<F, R>(ctx: WebApiContext, canvas: u8, f: F) -> Result<R, StatusCode>
+where
+    F: FnOnce(&grid::Flut<u32>) -> image::ImageResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    if ctx.grids.get(canvas as usize).is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tokio::task::spawn_blocking(move || f(&ctx.grids[canvas as usize]))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|err| {
+            tracing::error!("Error encoding image: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn png_snapshot(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let png = with_snapshot(ctx, canvas, |frame| {
+        let mut buf = Vec::new();
+        frame.encode_png(&mut buf)?;
+        Ok(buf)
+    })
+    .await?;
+    Ok(([(http::header::CONTENT_TYPE, "image/png")], png))
+}
+
+/// The canvas as packed rgb bytes row by row, the size is in the `x-width` and `x-height` headers
+async fn raw_snapshot(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let ((width, height), raw) = with_snapshot(ctx, canvas, |frame| {
+        Ok((frame.get_size(), frame.to_rgb_bytes()))
+    })
+    .await?;
+    Ok((
+        [
+            (
+                http::header::CONTENT_TYPE,
+                "application/octet-stream".to_string(),
+            ),
+            (http::HeaderName::from_static("x-width"), width.to_string()),
+            (
+                http::HeaderName::from_static("x-height"),
+                height.to_string(),
+            ),
+        ],
+        raw,
+    ))
+}
+
+/// Render one of the activity layers of `canvas` as a png, 404 if the canvas doesn't keep them
+async fn activity_png<F>(ctx: WebApiContext, canvas: u8, render: F) -> Result<Response, StatusCode>
+where
+    F: FnOnce(&Activity) -> RgbImage + Send + 'static,
+{
+    let activity = ctx
+        .grids
+        .get(canvas as usize)
+        .and_then(|grid| grid.activity())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let png = with_canvas(ctx, canvas, move |_| {
+        let image = render(&activity);
+        let mut buf = Vec::new();
+        image.write_with_encoder(PngEncoder::new(&mut buf))?;
+        Ok(buf)
+    })
+    .await?;
+    Ok(([(http::header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+/// How often every pixel was written, on a log scale from black to white
+async fn heatmap(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<Response, StatusCode> {
+    activity_png(ctx, canvas, Activity::heatmap).await
+}
+
+/// How long ago every pixel was written, the most recent are white and untouched ones black
+async fn age_map(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<Response, StatusCode> {
+    activity_png(ctx, canvas, Activity::age_map).await
+}
+
+async fn reset_activity(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&ctx, auth) {
+        return status;
+    }
+    match ctx
+        .grids
+        .get(canvas as usize)
+        .and_then(|grid| grid.activity())
+    {
+        Some(activity) => {
+            activity.reset();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Only let requests through that carry the admin token, 403 if there is no admin token
+fn check_admin(
+    ctx: &WebApiContext,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), StatusCode> {
+    let Some(token) = &ctx.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    match auth {
+        Some(TypedHeader(auth)) if auth.token() == token.as_ref() => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PixelOwner {
+    client: ClientId,
+    #[serde(flatten)]
+    info: Option<ClientInfo>,
+    time: DateTime<Utc>,
+}
+
+/// Who last wrote a pixel, `null` if nobody did since the server started
+async fn pixel_owner(
+    State(ctx): State<WebApiContext>,
+    Path((canvas, x, y)): Path<(u8, Coordinate, Coordinate)>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Option<PixelOwner>>, StatusCode> {
+    check_admin(&ctx, auth)?;
+    let grid = ctx
+        .grids
+        .get(canvas as usize)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let attribution = grid.attribution().ok_or(StatusCode::NOT_FOUND)?;
+    let idx = attribution
+        .index(x as usize, y as usize)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(attribution.owner(idx).map(|(client, time)| {
+        PixelOwner {
+            client,
+            info: client_info(client),
+            time,
+        }
+    })))
+}
+
+#[derive(Debug, Serialize)]
+struct RegionEntry {
+    id: u32,
+    #[serde(flatten)]
+    region: ProtectedRegion,
+}
+
+#[derive(Debug, Serialize)]
+struct RegionId {
+    id: u32,
+}
+
+fn canvas_grid(ctx: &WebApiContext, canvas: u8) -> Result<&grid::Flut<u32>, StatusCode> {
+    ctx.grids.get(canvas as usize).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// The regions of a canvas clients can't write to, public so bots can see why pixels don't stick
+async fn protected_regions(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+) -> Result<Json<Vec<RegionEntry>>, StatusCode> {
+    let regions = canvas_grid(&ctx, canvas)?.protection().regions();
+    Ok(Json(
+        regions
+            .into_iter()
+            .map(|(id, region)| RegionEntry { id, region })
+            .collect(),
+    ))
+}
+
+/// Protect a rectangle, masks have to be uploaded as an image instead
+async fn protect_rect(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(region): Json<ProtectedRegion>,
+) -> Result<Json<RegionId>, StatusCode> {
+    check_admin(&ctx, auth)?;
+    if !matches!(region, ProtectedRegion::Rect { .. }) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let id = canvas_grid(&ctx, canvas)?
+        .protection()
+        .add(region)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(Json(RegionId { id }))
+}
+
+#[derive(Debug, Deserialize)]
+struct MaskQuery {
+    #[serde(default)]
+    x: usize,
+    #[serde(default)]
+    y: usize,
+}
+
+/// Protect the bright, opaque pixels of the image in the body, placed at `x`, `y`
+async fn protect_mask(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    Query(MaskQuery { x, y }): Query<MaskQuery>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    body: Bytes,
+) -> Result<Json<RegionId>, StatusCode> {
+    check_admin(&ctx, auth)?;
+    canvas_grid(&ctx, canvas)?;
+    let region = tokio::task::spawn_blocking(move || {
+        image::load_from_memory(&body).map(|image| ProtectedRegion::mask(x, y, &image))
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let id = canvas_grid(&ctx, canvas)?
+        .protection()
+        .add(region)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(Json(RegionId { id }))
+}
+
+async fn unprotect(
+    State(ctx): State<WebApiContext>,
+    Path((canvas, id)): Path<(u8, u32)>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&ctx, auth) {
+        return status;
+    }
+    match canvas_grid(&ctx, canvas) {
+        Ok(grid) if grid.protection().remove(id) => StatusCode::NO_CONTENT,
+        Ok(_) => StatusCode::NOT_FOUND,
+        Err(status) => status,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RegionQuery {
+    #[serde(default)]
+    x: usize,
+    #[serde(default)]
+    y: usize,
+    width: Option<usize>,
+    height: Option<usize>,
+}
+
+/// Write the initial image (or fill) back to the whole canvas, or to the region in the query
+async fn reset_canvas(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    Query(region): Query<RegionQuery>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&ctx, auth) {
+        return status;
+    }
+    if ctx.grids.get(canvas as usize).is_none() {
+        return StatusCode::NOT_FOUND;
+    }
+    let reset = tokio::task::spawn_blocking(move || {
+        ctx.grids[canvas as usize].reset_region(
+            region.x,
+            region.y,
+            region.width.unwrap_or(usize::MAX),
+            region.height.unwrap_or(usize::MAX),
+        )
+    });
+    match reset.await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResizeQuery {
+    width: usize,
+    height: usize,
+    /// stretch the old pixels to the new size instead of keeping them at `anchor`
+    #[serde(default)]
+    scale: bool,
+    #[serde(default)]
+    anchor: Anchor,
+}
+
+/// Resize a canvas keeping what is on it, clients and viewers pick up the new size on their own
+async fn resize_canvas(
+    State(ctx): State<WebApiContext>,
+    Path(canvas): Path<u8>,
+    Query(query): Query<ResizeQuery>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> StatusCode {
+    if let Err(status) = check_admin(&ctx, auth) {
+        return status;
+    }
+    if ctx.grids.get(canvas as usize).is_none() {
+        return StatusCode::NOT_FOUND;
+    }
+    let mode = match query.scale {
+        true => ResizeMode::Scale,
+        false => ResizeMode::Anchor(query.anchor),
+    };
+    let resize = tokio::task::spawn_blocking(move || {
+        ctx.grids[canvas as usize].resize(query.width, query.height, mode)
+    });
+    match resize.await {
+        Ok(Ok(())) => StatusCode::NO_CONTENT,
+        Ok(Err(_)) => StatusCode::BAD_REQUEST,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }